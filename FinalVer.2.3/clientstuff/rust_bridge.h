@@ -3,6 +3,10 @@
 
 #include <gtk/gtk.h>
 
+// Implemented by the Rust client itself (src/bridge.rs), build it with
+// cargo build --release -p your-client-name and link ../../target/release/librust_client.so.
+// The callback runs on the GTK main loop, once per event line, without the newline.
+
 typedef void (*RustMessageCallback)(const char *msg, gpointer user_data);
 
 void rust_bridge_start(RustMessageCallback callback, gpointer user_data,const char *finalname);
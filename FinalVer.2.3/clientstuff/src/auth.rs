@@ -0,0 +1,67 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+use nameless_proto::auth::{self, AESKey, Greeting, Identity};
+
+pub const PASSWORD_VAR: &str = "NAMELESS_PASSWORD"; // used instead of asking, if set
+pub const IDENTITY_VAR: &str = "NAMELESS_IDENTITY"; // where our key is kept
+pub const DEFAULT_IDENTITY_FILE: &str = "identity.key";
+
+// Client side of the handshake in nameless_proto::auth, run right after the intro line.
+// An open server just sends the room key. A locked one sends a salt and a challenge,
+// we derive the key from the password and prove it, then make the server prove it back.
+// Either way we finish by signing the server's "who" with our identity.
+// `password` is only called when the server asks for one.
+pub fn handshake(stream: &mut (impl Read + Write), identity: &Identity, password: impl FnOnce() -> io::Result<String>) -> io::Result<AESKey> {
+    let key = match Greeting::parse(&read_line(stream)?)? {
+        Greeting::Key(key) => key,
+        Greeting::Auth { salt, challenge } => {
+            let key = auth::derive_key(&password()?, &salt);
+            writeln!(stream, "{}", auth::proof(&key, &challenge))?;
+            auth::check_answer(&key, &challenge, &read_line(stream)?)?;
+            key
+        }
+    };
+    let me = auth::identify(identity, &key, &read_line(stream)?)?;
+    writeln!(stream, "{}", me)?;
+    Ok(key)
+}
+
+// Our key, hex in a file only we can read, made on the first run. Servers hand our name
+// to whoever holds it, so it's as good as a password.
+pub fn load_identity(path: &Path) -> io::Result<Identity> {
+    match fs::read_to_string(path) {
+        Ok(text) => {
+            let bytes = hex::decode(text.trim()).ok().and_then(|bytes| bytes.try_into().ok());
+            bytes.map(|bytes| Identity::from_bytes(&bytes)).ok_or_else(|| invalid("the identity file is damaged"))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let identity = auth::new_identity();
+            let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+            writeln!(file, "{}", hex::encode(identity.to_bytes()))?;
+            Ok(identity)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// One byte at a time, frames follow straight after and must stay in the stream
+fn read_line(stream: &mut impl Read) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while line.len() < 512 {
+        stream.read_exact(&mut byte)?;
+        if byte[0] == b'\n' {
+            return String::from_utf8(line).map_err(|_| invalid("handshake line is not UTF-8"));
+        }
+        line.push(byte[0]);
+    }
+    Err(invalid("handshake line too long"))
+}
+
+fn invalid(text: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, text.to_string())
+}
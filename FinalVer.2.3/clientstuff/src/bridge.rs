@@ -0,0 +1,215 @@
+use std::{
+    ffi::{CStr, CString, c_char, c_int, c_uint, c_void},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+};
+use nameless_proto::frame::Line;
+use crate::Places;
+
+// What rust_bridge.h declares, built into librust_client.so for the GTK UI. The client runs
+// on a thread of the UI's own process, every event reaches the callback whole, one line
+// per call, on the GTK main loop and in order.
+
+const G_PRIORITY_DEFAULT: c_int = 0;
+const G_SOURCE_REMOVE: c_int = 0;
+
+// glib comes with the GTK process that loads us, so nothing to link against
+unsafe extern "C" {
+    fn g_idle_add_full(priority: c_int, function: unsafe extern "C" fn(*mut c_void) -> c_int, data: *mut c_void, notify: Option<unsafe extern "C" fn(*mut c_void)>) -> c_uint;
+}
+
+type RustMessageCallback = unsafe extern "C" fn(msg: *const c_char, user_data: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct Callback {
+    function: RustMessageCallback,
+    user_data: *mut c_void,
+}
+
+// Only ever called from the GTK main loop, the other threads just store it
+unsafe impl Send for Callback {}
+
+static CALLBACK: Mutex<Option<Callback>> = Mutex::new(None);
+static INPUT: Mutex<Option<mpsc::Sender<String>>> = Mutex::new(None); // lines for the client
+
+/// # Safety
+/// `finalname` is NULL or a C string, `callback` stays callable with `user_data` until rust_bridge_stop.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rust_bridge_start(callback: Option<RustMessageCallback>, user_data: *mut c_void, finalname: *const c_char) {
+    let username = if finalname.is_null() { String::new() } else { unsafe { CStr::from_ptr(finalname) }.to_string_lossy().to_string() };
+    start(username, callback.map(|function| Callback { function, user_data }), Places::from_env());
+}
+
+fn start(username: String, callback: Option<Callback>, places: Places) {
+    *CALLBACK.lock().unwrap() = callback;
+
+    // Starting again replaces the input, the previous client sees it end and hangs up
+    let (tx, rx) = mpsc::channel::<String>();
+    *INPUT.lock().unwrap() = Some(tx);
+    thread::spawn(move || {
+        if let Err(e) = crate::run_from(&username, rx.into_iter().map(Ok), Arc::new(dispatch), places) {
+            eprintln!("Client stopped: {}", e);
+            dispatch(Line::new("error").arg(format!("disconnected: {}", e)));
+        }
+    });
+}
+
+/// # Safety
+/// `msg` is NULL or a C string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rust_bridge_send(msg: *const c_char) {
+    if msg.is_null() {
+        return;
+    }
+    let msg = unsafe { CStr::from_ptr(msg) }.to_string_lossy();
+    if let Some(input) = INPUT.lock().unwrap().as_ref() {
+        // Same as the old pipe, every line of it is its own input line
+        for line in msg.split('\n') {
+            input.send(line.trim_end_matches('\r').to_string()).ok();
+        }
+    }
+}
+
+// Ends the input like /quit would. Events still queued on the main loop are dropped.
+#[unsafe(no_mangle)]
+pub extern "C" fn rust_bridge_stop() {
+    INPUT.lock().unwrap().take();
+    CALLBACK.lock().unwrap().take();
+}
+
+// Any client thread, queues the event for the main loop
+fn dispatch(event: Line) {
+    let Ok(text) = CString::new(event.to_string().replace('\0', "")) else {
+        return;
+    };
+    unsafe {
+        g_idle_add_full(G_PRIORITY_DEFAULT, deliver, text.into_raw().cast(), None);
+    }
+}
+
+// On the main loop, idle sources of one priority run in the order they were added
+unsafe extern "C" fn deliver(data: *mut c_void) -> c_int {
+    let text = unsafe { CString::from_raw(data.cast()) };
+    let callback = *CALLBACK.lock().unwrap();
+    if let Some(Callback { function, user_data }) = callback {
+        unsafe { function(text.as_ptr(), user_data) };
+    }
+    G_SOURCE_REMOVE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        env,
+        io::{BufRead, BufReader, ErrorKind, Write},
+        net::TcpListener,
+        time::{Duration, Instant},
+    };
+    use nameless_proto::{auth::{self, Greeting}, frame};
+
+    type Idle = (unsafe extern "C" fn(*mut c_void) -> c_int, usize);
+
+    // Stands in for glib, the test thread plays the main loop
+    static IDLE: Mutex<Vec<Idle>> = Mutex::new(Vec::new());
+    static EVENTS: Mutex<Vec<(String, usize)>> = Mutex::new(Vec::new());
+
+    #[unsafe(no_mangle)]
+    unsafe extern "C" fn g_idle_add_full(_priority: c_int, function: unsafe extern "C" fn(*mut c_void) -> c_int, data: *mut c_void, _notify: Option<unsafe extern "C" fn(*mut c_void)>) -> c_uint {
+        IDLE.lock().unwrap().push((function, data as usize));
+        1
+    }
+
+    fn run_main_loop() {
+        let queued: Vec<Idle> = IDLE.lock().unwrap().drain(..).collect();
+        for (function, data) in queued {
+            unsafe { function(data as *mut c_void) };
+        }
+    }
+
+    unsafe extern "C" fn on_event(msg: *const c_char, user_data: *mut c_void) {
+        let msg = unsafe { CStr::from_ptr(msg) }.to_string_lossy().to_string();
+        EVENTS.lock().unwrap().push((msg, user_data as usize));
+    }
+
+    fn wait_for(what: &str) -> String {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            run_main_loop();
+            if let Some((event, _)) = EVENTS.lock().unwrap().iter().find(|(event, _)| event.contains(what)) {
+                return event.clone();
+            }
+            assert!(Instant::now() < deadline, "no {:?} event, got {:?}", what, EVENTS.lock().unwrap());
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn events_reach_the_callback_whole_and_stop_hangs_up() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let lobby = TcpListener::bind("127.0.0.1:0").unwrap();
+        let identity = env::temp_dir().join(format!("nameless-bridge-{}.key", std::process::id()));
+        let places = Places { lobby: lobby.local_addr().unwrap().to_string(), identity: identity.clone() };
+        let lobby = thread::spawn(move || {
+            let (stream, _) = lobby.accept().unwrap();
+            let mut request = String::new();
+            BufReader::new(&stream).read_line(&mut request).unwrap();
+            writeln!(&stream, "{} open", server_addr).unwrap();
+            assert!(request.starts_with("client alice"));
+        });
+
+        let user_data = 42usize as *mut c_void;
+        start("alice".to_string(), Some(Callback { function: on_event, user_data }), places);
+
+        let (stream, _) = server.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut intro = String::new();
+        reader.read_line(&mut intro).unwrap();
+        assert_eq!(intro, "client alice\n");
+        let key = [7u8; 32];
+        writeln!(&stream, "{}", Greeting::Key(key)).unwrap();
+        let challenge = [8u8; 32];
+        writeln!(&stream, "{}", auth::who(&challenge)).unwrap();
+        let mut me = String::new();
+        reader.read_line(&mut me).unwrap();
+        assert!(auth::check_identity(&key, &challenge, &me).is_some());
+        let cipher = auth::cipher(&key);
+
+        // Far longer than the old pipe's 1024 byte reads
+        let long = "word ".repeat(1000);
+        frame::write_frame(&mut &stream, &cipher, &Line::new("roster").arg("alice").arg("bob")).unwrap();
+        frame::write_frame(&mut &stream, &cipher, &Line::new("msg").tag("id", 1).arg("bob").arg(long.trim_end())).unwrap();
+        assert_eq!(wait_for("roster"), "roster alice bob");
+        let msg = wait_for("msg bob");
+        assert!(msg.ends_with(long.trim_end()) && !msg.contains('\n'));
+        assert!(EVENTS.lock().unwrap().iter().all(|(_, data)| *data == 42));
+
+        unsafe { rust_bridge_send(c"hello there".as_ptr()) };
+        let said = loop {
+            let line = Line::parse(&frame::read_frame(&mut reader, &cipher).unwrap()).unwrap();
+            if line.cmd == "say" {
+                break line;
+            }
+        };
+        assert_eq!(said.get(0), Some("hello there"));
+        assert_eq!(wait_for("msg alice"), "@local=1 msg alice :hello there");
+        wait_for("sent 1");
+
+        // The client hangs up, and nothing reaches the UI after stop
+        rust_bridge_stop();
+        loop {
+            match frame::read_frame(&mut reader, &cipher) {
+                Ok(_) => continue,
+                Err(e) => break assert_eq!(e.kind(), ErrorKind::UnexpectedEof),
+            }
+        }
+        let before = EVENTS.lock().unwrap().len();
+        dispatch(Line::new("system").arg("too late"));
+        run_main_loop();
+        assert_eq!(EVENTS.lock().unwrap().len(), before);
+        lobby.join().unwrap();
+        std::fs::remove_file(identity).ok();
+    }
+}
@@ -0,0 +1,372 @@
+use nameless_proto::frame::Line;
+use crate::search::SearchQuery;
+
+// What a line typed in the UI turns into
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Send(Line), // frame for the server
+    Help,       // print HELP locally
+    Receipts(bool), // whether we tell senders we've read their messages
+    SendFile { to: Option<String>, path: String }, // offer a file to the room or one user
+    Accept(u64),       // download an offered file
+    Reject(u64),       // turn an offer down, or stop its download
+    Downloads(String), // where downloads go from now on
+    Search(SearchQuery), // look through the server's history
+    More,              // the next page of the last search
+    Quit,
+}
+
+pub const HELP: &[&str] = &[
+    "/msg <user> <text>  send a private message",
+    "/me <action>        describe what you're doing",
+    "/reply <id> <text>  answer a message in its thread",
+    "/send <path>        offer a file to the room",
+    "/sendto <user> <path> offer a file to one person",
+    "/accept <id>        download an offered file, resumes if it was cut off",
+    "/reject <id>        turn a file down or stop downloading it",
+    "/downloads <dir>    where downloaded files are saved",
+    "/thread <id>        show a whole thread",
+    "/search [in:<room>] [from:<user>] [since:<date|7d>] [until:<date|7d>] <words>",
+    "                    find old messages, dates are YYYY-MM-DD",
+    "/more               older results for the last search",
+    "/edit <id> <text>   change one of your messages",
+    "/delete <id>        take one of your messages back",
+    "/react <id> <emoji> react to a message, /unreact takes it off",
+    "/nick <name>        change your name",
+    "/who                list who is online",
+    "/join <room>        switch to another room",
+    "/leave              go back to the default room",
+    "/receipts on|off    send read receipts or not",
+    "/kick <user> [reason]               moderators: disconnect someone",
+    "/ban <user|ip> [10m|2h|7d] [reason] moderators: keep someone out, for good without a time",
+    "/unban <user|ip>                    moderators: lift a ban",
+    "/mute <user> [10m|2h|7d]            moderators: stop someone talking",
+    "/unmute <user>                      moderators: let them talk again",
+    "/op <user>, /deop <user>            owner: make or unmake a moderator",
+    "/invite [room] [2h|7d|perm] [uses]  make an invite code, for this room and 1d by default",
+    "/motd                               show the message of the day again",
+    "/motd <text> | <next line>          owner: change it, /motd clear removes it",
+    "/quit               disconnect",
+    "/help               show this list",
+    "start a message with // to send a literal /",
+];
+
+pub fn parse(input: &str) -> Result<Command, String> {
+    // "//text" escapes the command layer and sends "/text"
+    if input.starts_with("//") {
+        return Ok(Command::Send(Line::new("say").arg(&input[1..])));
+    }
+    let Some(body) = input.strip_prefix('/') else {
+        return Ok(Command::Send(Line::new("say").arg(input)));
+    };
+
+    let (name, rest) = match body.split_once(char::is_whitespace) {
+        Some((name, rest)) => (name, rest.trim()),
+        None => (body, ""),
+    };
+
+    match name {
+        "msg" => match rest.split_once(char::is_whitespace) {
+            Some((user, text)) if !text.trim().is_empty() => {
+                Ok(Command::Send(Line::new("dm").arg(user).arg(text.trim_start())))
+            }
+            _ => Err("usage: /msg <user> <text>".to_string()),
+        },
+        "me" if !rest.is_empty() => Ok(Command::Send(Line::new("me").arg(rest))),
+        "me" => Err("usage: /me <action>".to_string()),
+        "reply" => match rest.split_once(char::is_whitespace) {
+            Some((id, text)) if is_id(id) && !text.trim().is_empty() => {
+                Ok(Command::Send(Line::new("say").tag("in_reply_to", id).arg(text.trim_start())))
+            }
+            _ => Err("usage: /reply <id> <text>".to_string()),
+        },
+        "thread" if is_id(rest) => Ok(Command::Send(Line::new("thread").arg(rest))),
+        "thread" => Err("usage: /thread <id>".to_string()),
+        "search" => SearchQuery::parse(rest).map(Command::Search),
+        "more" if rest.is_empty() => Ok(Command::More),
+        "more" => Err("usage: /more".to_string()),
+        "send" if !rest.is_empty() => Ok(Command::SendFile { to: None, path: rest.to_string() }),
+        "send" => Err("usage: /send <path>".to_string()),
+        "sendto" => match rest.split_once(char::is_whitespace) {
+            Some((user, path)) if is_word(user) && !path.trim().is_empty() => {
+                Ok(Command::SendFile { to: Some(user.to_string()), path: path.trim_start().to_string() })
+            }
+            _ => Err("usage: /sendto <user> <path>".to_string()),
+        },
+        "accept" | "reject" => match rest.parse() {
+            Ok(id) if is_id(rest) => Ok(if name == "accept" { Command::Accept(id) } else { Command::Reject(id) }),
+            _ => Err(format!("usage: /{} <id>", name)),
+        },
+        "downloads" if !rest.is_empty() => Ok(Command::Downloads(rest.to_string())),
+        "downloads" => Err("usage: /downloads <dir>".to_string()),
+        "edit" => match rest.split_once(char::is_whitespace) {
+            Some((id, text)) if is_id(id) && !text.trim().is_empty() => {
+                Ok(Command::Send(Line::new("edit").arg(id).arg(text.trim_start())))
+            }
+            _ => Err("usage: /edit <id> <text>".to_string()),
+        },
+        "react" | "unreact" => match rest.split_once(char::is_whitespace) {
+            Some((id, emoji)) if is_id(id) && is_word(emoji.trim()) => {
+                Ok(Command::Send(Line::new(name).arg(id).arg(emoji.trim())))
+            }
+            _ => Err(format!("usage: /{} <id> <emoji>", name)),
+        },
+        "delete" if is_id(rest) => Ok(Command::Send(Line::new("delete").arg(rest))),
+        "delete" => Err("usage: /delete <id>".to_string()),
+        "nick" => single_word(rest, "usage: /nick <name>").map(|n| Command::Send(Line::new("nick").arg(n))),
+        "join" => single_word(rest, "usage: /join <room>").map(|r| Command::Send(Line::new("join").arg(r))),
+        "leave" if rest.is_empty() => Ok(Command::Send(Line::new("leave"))),
+        "leave" => Err("usage: /leave".to_string()),
+        "who" if rest.is_empty() => Ok(Command::Send(Line::new("who"))),
+        "who" => Err("usage: /who".to_string()),
+        // sent by the UI while the input box is being edited, not listed in HELP
+        "typing" => match rest {
+            "start" | "stop" => Ok(Command::Send(Line::new("typing").arg(rest))),
+            _ => Err("usage: /typing start|stop".to_string()),
+        },
+        "kick" => match rest.split_once(char::is_whitespace) {
+            Some((user, reason)) if is_word(user) => Ok(Command::Send(Line::new("kick").arg(user).arg(reason.trim()))),
+            None if is_word(rest) => Ok(Command::Send(Line::new("kick").arg(rest))),
+            _ => Err("usage: /kick <user> [reason]".to_string()),
+        },
+        "ban" => {
+            let (target, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            if !is_word(target) {
+                return Err("usage: /ban <user|ip> [10m|2h|7d] [reason]".to_string());
+            }
+            let rest = rest.trim_start();
+            // no duration means for good
+            let (duration, reason) = match rest.split_once(char::is_whitespace) {
+                Some((word, reason)) if is_duration(word) => (word, reason.trim()),
+                _ if is_duration(rest) => (rest, ""),
+                _ => ("perm", rest),
+            };
+            Ok(Command::Send(Line::new("ban").arg(target).arg(duration).arg(reason)))
+        }
+        "mute" => match rest.split_once(char::is_whitespace) {
+            Some((user, duration)) if is_word(user) && is_duration(duration.trim()) => {
+                Ok(Command::Send(Line::new("mute").arg(user).arg(duration.trim())))
+            }
+            None if is_word(rest) => Ok(Command::Send(Line::new("mute").arg(rest).arg("perm"))),
+            _ => Err("usage: /mute <user> [10m|2h|7d]".to_string()),
+        },
+        "unban" | "unmute" | "op" | "deop" => {
+            single_word(rest, &format!("usage: /{} <user>", name)).map(|target| Command::Send(Line::new(name).arg(target)))
+        }
+        // words in any order: durations, counts and a room name
+        "invite" => {
+            let mut frame = Line::new("invite");
+            for word in rest.split_whitespace() {
+                frame = if is_duration(word) {
+                    frame.tag("expires", word)
+                } else if is_id(word) {
+                    frame.tag("uses", word)
+                } else if is_word(word) {
+                    frame.tag("room", word)
+                } else {
+                    return Err("usage: /invite [room] [2h|7d|perm] [uses]".to_string());
+                };
+            }
+            Ok(Command::Send(frame))
+        }
+        // the input box is one line, " | " starts a new one
+        "motd" => match rest {
+            "" => Ok(Command::Send(Line::new("motd"))),
+            "clear" => Ok(Command::Send(Line::new("motd").arg(""))),
+            text => Ok(Command::Send(Line::new("motd").arg(text.split(" | ").collect::<Vec<_>>().join("\n")))),
+        },
+        "receipts" => match rest {
+            "on" => Ok(Command::Receipts(true)),
+            "off" => Ok(Command::Receipts(false)),
+            _ => Err("usage: /receipts on|off".to_string()),
+        },
+        "quit" => Ok(Command::Quit),
+        "help" => Ok(Command::Help),
+        "" => Err("empty command, type /help for a list".to_string()),
+        other => Err(format!("unknown command '/{}', type /help for a list", other)),
+    }
+}
+
+// Message ids are the numbers the server hands out
+fn is_id(word: &str) -> bool {
+    !word.is_empty() && word.chars().all(|c| c.is_ascii_digit())
+}
+
+// Names, rooms and emoji travel as a single protocol word, so no spaces and no leading ':'
+fn is_word(word: &str) -> bool {
+    !word.is_empty() && !word.contains(char::is_whitespace) && !word.starts_with(':')
+}
+
+// "30s", "10m", "2h", "7d" or "perm", the server works out the rest
+fn is_duration(word: &str) -> bool {
+    word == "perm" || (word.len() > 1 && word.ends_with(['s', 'm', 'h', 'd']) && is_id(&word[..word.len() - 1]))
+}
+
+fn single_word<'a>(rest: &'a str, usage: &str) -> Result<&'a str, String> {
+    if is_word(rest) { Ok(rest) } else { Err(usage.to_string()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(line: Line) -> Result<Command, String> {
+        Ok(Command::Send(line))
+    }
+
+    #[test]
+    fn plain_text_is_a_room_message() {
+        assert_eq!(parse("hello there"), send(Line::new("say").arg("hello there")));
+    }
+
+    #[test]
+    fn double_slash_sends_a_literal_slash() {
+        assert_eq!(parse("//shrug"), send(Line::new("say").arg("/shrug")));
+    }
+
+    #[test]
+    fn msg_needs_a_user_and_text() {
+        assert_eq!(parse("/msg bob  see you"), send(Line::new("dm").arg("bob").arg("see you")));
+        assert!(parse("/msg bob").is_err());
+        assert!(parse("/msg bob   ").is_err());
+        assert!(parse("/msg").is_err());
+    }
+
+    #[test]
+    fn me_sends_an_action() {
+        assert_eq!(parse("/me waves"), send(Line::new("me").arg("waves")));
+        assert!(parse("/me").is_err());
+    }
+
+    #[test]
+    fn edit_and_delete_need_a_message_id() {
+        assert_eq!(parse("/edit 12 fixed typo"), send(Line::new("edit").arg("12").arg("fixed typo")));
+        assert_eq!(parse("/delete 12"), send(Line::new("delete").arg("12")));
+        assert!(parse("/edit 12").is_err());
+        assert!(parse("/edit twelve fixed").is_err());
+        assert!(parse("/delete").is_err());
+        assert!(parse("/delete 12 13").is_err());
+    }
+
+    #[test]
+    fn reply_tags_the_parent_id() {
+        assert_eq!(parse("/reply 4  me too"), send(Line::new("say").tag("in_reply_to", "4").arg("me too")));
+        assert_eq!(parse("/thread 4"), send(Line::new("thread").arg("4")));
+        assert!(parse("/reply 4").is_err());
+        assert!(parse("/reply four me too").is_err());
+        assert!(parse("/thread").is_err());
+    }
+
+    #[test]
+    fn file_commands() {
+        let to_room = Command::SendFile { to: None, path: "my notes.txt".to_string() };
+        let to_bob = Command::SendFile { to: Some("bob".to_string()), path: "/tmp/a b.png".to_string() };
+        assert_eq!(parse("/send my notes.txt"), Ok(to_room));
+        assert_eq!(parse("/sendto bob /tmp/a b.png"), Ok(to_bob));
+        assert_eq!(parse("/accept 3"), Ok(Command::Accept(3)));
+        assert_eq!(parse("/reject 3"), Ok(Command::Reject(3)));
+        assert_eq!(parse("/downloads ~/Downloads"), Ok(Command::Downloads("~/Downloads".to_string())));
+        assert!(parse("/send").is_err());
+        assert!(parse("/sendto bob").is_err());
+        assert!(parse("/accept").is_err());
+        assert!(parse("/accept +3").is_err());
+    }
+
+    #[test]
+    fn moderation_commands() {
+        assert_eq!(parse("/kick bob spamming links"), send(Line::new("kick").arg("bob").arg("spamming links")));
+        assert_eq!(parse("/kick bob"), send(Line::new("kick").arg("bob")));
+        assert_eq!(parse("/ban bob 2h flooding"), send(Line::new("ban").arg("bob").arg("2h").arg("flooding")));
+        assert_eq!(parse("/ban 10.0.0.7"), send(Line::new("ban").arg("10.0.0.7").arg("perm").arg("")));
+        assert_eq!(parse("/ban bob rude to everyone"), send(Line::new("ban").arg("bob").arg("perm").arg("rude to everyone")));
+        assert_eq!(parse("/mute bob 10m"), send(Line::new("mute").arg("bob").arg("10m")));
+        assert_eq!(parse("/mute bob"), send(Line::new("mute").arg("bob").arg("perm")));
+        assert_eq!(parse("/op carol"), send(Line::new("op").arg("carol")));
+        assert_eq!(parse("/unban 10.0.0.7"), send(Line::new("unban").arg("10.0.0.7")));
+        assert!(parse("/ban").is_err());
+        assert!(parse("/mute bob soon").is_err());
+        assert!(parse("/deop").is_err());
+    }
+
+    #[test]
+    fn invite_takes_room_expiry_and_uses_in_any_order() {
+        assert_eq!(parse("/invite"), send(Line::new("invite")));
+        assert_eq!(parse("/invite 7d lounge 5"), send(Line::new("invite").tag("expires", "7d").tag("room", "lounge").tag("uses", "5")));
+        assert_eq!(parse("/invite perm"), send(Line::new("invite").tag("expires", "perm")));
+        assert!(parse("/invite :lounge").is_err());
+    }
+
+    #[test]
+    fn search_and_more() {
+        let query = SearchQuery { words: "lunch".to_string(), from: Some("bob".to_string()), ..Default::default() };
+        assert_eq!(parse("/search from:bob lunch"), Ok(Command::Search(query)));
+        assert_eq!(parse("/more"), Ok(Command::More));
+        assert_eq!(parse("/search"), Err(crate::search::USAGE.to_string()));
+    }
+
+    #[test]
+    fn motd_shows_sets_or_clears() {
+        assert_eq!(parse("/motd"), send(Line::new("motd")));
+        assert_eq!(parse("/motd be nice | no spam"), send(Line::new("motd").arg("be nice\nno spam")));
+        assert_eq!(parse("/motd clear"), send(Line::new("motd").arg("")));
+    }
+
+    #[test]
+    fn react_takes_an_id_and_one_emoji() {
+        assert_eq!(parse("/react 7 👍"), send(Line::new("react").arg("7").arg("👍")));
+        assert_eq!(parse("/unreact 7  👍"), send(Line::new("unreact").arg("7").arg("👍")));
+        assert!(parse("/react 7").is_err());
+        assert!(parse("/react x 👍").is_err());
+        assert!(parse("/react 7 👍 👎").is_err());
+    }
+
+    #[test]
+    fn nick_and_join_take_one_word() {
+        assert_eq!(parse("/nick carol"), send(Line::new("nick").arg("carol")));
+        assert_eq!(parse("/join  rust "), send(Line::new("join").arg("rust")));
+        assert!(parse("/nick two words").is_err());
+        assert!(parse("/nick :colon").is_err());
+        assert!(parse("/join").is_err());
+    }
+
+    #[test]
+    fn who_and_leave_take_no_arguments() {
+        assert_eq!(parse("/who"), send(Line::new("who")));
+        assert_eq!(parse("/leave"), send(Line::new("leave")));
+        assert!(parse("/who everyone").is_err());
+        assert!(parse("/leave now").is_err());
+    }
+
+    #[test]
+    fn typing_takes_start_or_stop() {
+        assert_eq!(parse("/typing start"), send(Line::new("typing").arg("start")));
+        assert_eq!(parse("/typing stop"), send(Line::new("typing").arg("stop")));
+        assert!(parse("/typing").is_err());
+        assert!(parse("/typing maybe").is_err());
+    }
+
+    #[test]
+    fn local_commands() {
+        assert_eq!(parse("/receipts off"), Ok(Command::Receipts(false)));
+        assert_eq!(parse("/receipts on"), Ok(Command::Receipts(true)));
+        assert!(parse("/receipts").is_err());
+        assert_eq!(parse("/quit"), Ok(Command::Quit));
+        assert_eq!(parse("/quit bye"), Ok(Command::Quit));
+        assert_eq!(parse("/help"), Ok(Command::Help));
+    }
+
+    #[test]
+    fn unknown_commands_are_reported() {
+        let err = parse("/dance").unwrap_err();
+        assert!(err.contains("/dance"));
+        assert!(parse("/").is_err());
+    }
+
+    #[test]
+    fn frames_survive_the_wire_format() {
+        let Ok(Command::Send(line)) = parse("/msg bob :) hi") else {
+            panic!("expected a frame");
+        };
+        assert_eq!(Line::parse(&line.to_string()), Some(line));
+    }
+}
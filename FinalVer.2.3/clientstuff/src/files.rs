@@ -0,0 +1,384 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+use aes_gcm::Aes256Gcm;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use sha2::{Digest, Sha256};
+use nameless_proto::frame::{self, Line};
+use crate::Events;
+
+pub type SharedFiles = Arc<Mutex<Files>>;
+pub type SharedWriter = Arc<Mutex<TcpStream>>; // frames from the writer thread and uploads take turns
+
+pub const DOWNLOAD_DIR_VAR: &str = "NAMELESS_DOWNLOADS";
+pub const DEFAULT_DOWNLOAD_DIR: &str = "downloads";
+const CHUNK_SIZE: usize = 32 * 1024; // base64 makes it ~44K, well inside the u16 frame size
+
+// A file someone offered us. Downloads go to "<sha256>.part" in the download directory
+// so an interrupted one can pick up where it stopped, even if the file is offered again.
+// Only one download of the same content runs at a time, they'd share the part file.
+struct Incoming {
+    name: String,
+    size: u64,
+    sha256: String,
+    received: u64,
+    accepted: bool,
+}
+
+// A file we offered, streamed separately to everyone who accepts it
+struct Outgoing {
+    path: PathBuf,
+    size: u64,
+    stopped: HashSet<String>, // receivers who rejected it or left part way
+}
+
+pub struct Files {
+    download_dir: PathBuf,
+    incoming: HashMap<u64, Incoming>,
+    unacked: HashMap<String, (PathBuf, u64)>, // local id -> file we offered, until "offered" names the transfer
+    outgoing: HashMap<u64, Outgoing>,
+}
+
+impl Files {
+    pub fn new(download_dir: PathBuf) -> Self {
+        Files { download_dir, incoming: HashMap::new(), unacked: HashMap::new(), outgoing: HashMap::new() }
+    }
+
+    pub fn set_download_dir(&mut self, dir: PathBuf) {
+        self.download_dir = dir;
+    }
+
+    // "accept <transfer> <offset>", the offset being however much an earlier try already saved
+    pub fn accept(&mut self, id: u64) -> Result<Line, String> {
+        let part = self.part_path(id).ok_or(format!("no file offer {}", id))?;
+        let sha256 = &self.incoming[&id].sha256;
+        if let Some((other, _)) = self.incoming.iter().find(|(other, i)| **other != id && i.accepted && i.sha256 == *sha256) {
+            return Err(format!("the same file is already downloading as offer {}", other));
+        }
+        let incoming = self.incoming.get_mut(&id).ok_or(format!("no file offer {}", id))?;
+        let saved = fs::metadata(&part).map(|m| m.len()).unwrap_or(0);
+        if saved > incoming.size {
+            fs::remove_file(&part).map_err(|e| e.to_string())?;
+        }
+        fs::create_dir_all(&self.download_dir).map_err(|e| format!("can't use {}: {}", self.download_dir.display(), e))?;
+        incoming.received = if saved > incoming.size { 0 } else { saved };
+        incoming.accepted = true;
+        Ok(Line::new("accept").arg(id.to_string()).arg(incoming.received.to_string()))
+    }
+
+    pub fn reject(&mut self, id: u64) -> Result<Line, String> {
+        self.incoming.remove(&id).ok_or(format!("no file offer {}", id))?;
+        Ok(Line::new("reject").arg(id.to_string()))
+    }
+
+    fn part_path(&self, id: u64) -> Option<PathBuf> {
+        let incoming = self.incoming.get(&id)?;
+        Some(self.download_dir.join(format!("{}.part", incoming.sha256)))
+    }
+}
+
+// "offer <size> <sha256> :<name>" for a local file, "@to=<user>" when it's for one person.
+// The whole file is hashed up front so the receiver can check what they got, before
+// taking the lock so a big file doesn't hold up incoming chunks.
+pub fn offer(files: &SharedFiles, local: &str, path: &Path, to: Option<&str>) -> io::Result<Line> {
+    let size = fs::metadata(path)?.len();
+    let sha256 = hash_file(path)?;
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("file").to_string();
+    files.lock().unwrap().unacked.insert(local.to_string(), (path.to_path_buf(), size));
+
+    let mut line = Line::new("offer");
+    if let Some(to) = to {
+        line = line.tag("to", to);
+    }
+    Ok(line.arg(size.to_string()).arg(sha256).arg(name))
+}
+
+// Handles a file transfer frame from the server and returns the event to print, if any:
+//   offer <from> <size> <sha256> :<name>  "@id=<transfer>", "private" for direct offers
+//   offered <transfer> :<name>            our offer went out under that id
+//   accepted <transfer> <user> <offset>   <user> wants our file, the upload starts
+//   rejected|cancelled <transfer> <user>  <user> won't take (the rest of) it
+//   cancelled <transfer>                  the sender left, the partial download is kept
+//   progress <transfer> <done> <size>     after every chunk, "@to=<user>" on uploads
+//   uploaded <transfer> <user>            every chunk has gone out
+//   received <transfer> :<path>           downloaded and checked
+//   failed <transfer> :<reason>
+pub fn handle(files: &SharedFiles, writer: &SharedWriter, cipher: &Arc<Aes256Gcm>, events: &Events, line: Line) -> Option<Line> {
+    let id = match line.cmd.as_str() {
+        "offer" => line.get_tag("id"),
+        _ => line.get(0),
+    };
+    let id: u64 = id?.parse().ok()?;
+    let mut files_lock = files.lock().unwrap();
+    match line.cmd.as_str() {
+        "offer" => {
+            let (size, sha256, name) = (line.get(1)?.parse().ok()?, line.get(2).filter(|hash| is_sha256(hash))?, line.get(3)?);
+            let incoming = Incoming { name: name.to_string(), size, sha256: sha256.to_string(), received: 0, accepted: false };
+            files_lock.incoming.insert(id, incoming);
+            Some(line)
+        }
+        "offered" => {
+            let local = line.get_tag("local")?;
+            let (path, size) = files_lock.unacked.remove(local)?;
+            let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+            files_lock.outgoing.insert(id, Outgoing { path, size, stopped: HashSet::new() });
+            Some(Line { args: vec![id.to_string(), name], ..line })
+        }
+        "accept" => {
+            let (user, offset) = (line.get(1)?.to_string(), line.get(2)?.parse::<u64>().ok()?);
+            let outgoing = files_lock.outgoing.get_mut(&id)?;
+            outgoing.stopped.remove(&user);
+            let (path, size) = (outgoing.path.clone(), outgoing.size);
+            let (files, writer, cipher, events) = (Arc::clone(files), Arc::clone(writer), Arc::clone(cipher), Arc::clone(events));
+            thread::spawn(move || upload(&files, &writer, &cipher, &events, id, &user, &path, size, offset));
+            Some(Line { cmd: "accepted".to_string(), ..line })
+        }
+        "reject" | "cancel" if line.get(1).is_some() => {
+            if let Some(outgoing) = files_lock.outgoing.get_mut(&id) {
+                outgoing.stopped.insert(line.get(1)?.to_string());
+            }
+            let cmd = if line.cmd == "reject" { "rejected" } else { "cancelled" };
+            Some(Line { cmd: cmd.to_string(), ..line })
+        }
+        "cancel" => {
+            files_lock.incoming.remove(&id)?;
+            Some(Line { cmd: "cancelled".to_string(), ..line })
+        }
+        "chunk" => {
+            let event = match save_chunk(&mut files_lock, id, line.get(1)?, line.get(2)?) {
+                Ok((received, size)) => Line::new("progress").arg(id.to_string()).arg(received.to_string()).arg(size.to_string()),
+                Err(reason) => {
+                    files_lock.incoming.remove(&id);
+                    frame::write_frame(&mut *writer.lock().unwrap(), cipher, &Line::new("reject").arg(id.to_string())).ok();
+                    Line::new("failed").arg(id.to_string()).arg(reason)
+                }
+            };
+            Some(event)
+        }
+        "done" => {
+            let part = files_lock.part_path(id)?;
+            let incoming = files_lock.incoming.remove(&id)?;
+            let event = match finish_download(&files_lock.download_dir, &part, &incoming) {
+                Ok(path) => Line::new("received").arg(id.to_string()).arg(path.display().to_string()),
+                Err(reason) => Line::new("failed").arg(id.to_string()).arg(reason),
+            };
+            Some(event)
+        }
+        _ => None,
+    }
+}
+
+// Chunks only count in order, the server relays them as the sender wrote them
+fn save_chunk(files: &mut Files, id: u64, offset: &str, data: &str) -> Result<(u64, u64), String> {
+    let part = files.part_path(id).ok_or("no such download")?;
+    let incoming = files.incoming.get_mut(&id).filter(|i| i.accepted).ok_or("no such download")?;
+    if offset.parse::<u64>().ok() != Some(incoming.received) {
+        return Err(format!("chunk out of order at {}", offset));
+    }
+    let bytes = STANDARD.decode(data).map_err(|_| "damaged chunk".to_string())?;
+    if incoming.received + bytes.len() as u64 > incoming.size {
+        return Err("more data than offered".to_string());
+    }
+
+    let write = || -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).write(true).truncate(false).open(&part)?;
+        file.seek(SeekFrom::Start(incoming.received))?;
+        file.write_all(&bytes)
+    };
+    write().map_err(|e| format!("can't write {}: {}", part.display(), e))?;
+    incoming.received += bytes.len() as u64;
+    Ok((incoming.received, incoming.size))
+}
+
+// Checks the hash and moves the finished download to its real name
+fn finish_download(dir: &Path, part: &Path, incoming: &Incoming) -> Result<PathBuf, String> {
+    let sha256 = hash_file(part).map_err(|e| e.to_string())?;
+    if incoming.received != incoming.size || sha256 != incoming.sha256 {
+        fs::remove_file(part).ok();
+        return Err("checksum mismatch, the file was thrown away".to_string());
+    }
+    let target = free_path(dir, &incoming.name);
+    fs::rename(part, &target).map_err(|e| e.to_string())?;
+    Ok(target)
+}
+
+// Only the bare file name is used, "name (1).ext" and so on if it's taken
+fn free_path(dir: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name).file_name().and_then(|n| n.to_str()).unwrap_or("download");
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    let mut candidate = dir.join(name);
+    let mut n = 1;
+    while candidate.exists() {
+        candidate = dir.join(format!("{} ({}){}", stem, n, ext));
+        n += 1;
+    }
+    candidate
+}
+
+// Streams the file from `offset` as "chunk <transfer> <user> <offset> :<base64>" frames,
+// then "done <transfer> <user>". Stops early once the receiver rejects it or leaves.
+#[allow(clippy::too_many_arguments)]
+fn upload(files: &SharedFiles, writer: &SharedWriter, cipher: &Aes256Gcm, events: &Events, id: u64, user: &str, path: &Path, size: u64, offset: u64) {
+    let progress = |sent: u64| Line::new("progress").tag("to", user).arg(id.to_string()).arg(sent.to_string()).arg(size.to_string());
+    let stopped = || files.lock().unwrap().outgoing.get(&id).is_none_or(|o| o.stopped.contains(user));
+    let send = |line: &Line| frame::write_frame(&mut *writer.lock().unwrap(), cipher, line);
+
+    let result = (|| -> io::Result<bool> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut sent = offset;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        while sent < size {
+            if stopped() {
+                return Ok(false);
+            }
+            let n = file.read(&mut buf[..CHUNK_SIZE.min((size - sent) as usize)])?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file got shorter"));
+            }
+            let chunk = Line::new("chunk").arg(id.to_string()).arg(user).arg(sent.to_string()).arg(STANDARD.encode(&buf[..n]));
+            send(&chunk)?;
+            sent += n as u64;
+            events(progress(sent));
+        }
+        send(&Line::new("done").arg(id.to_string()).arg(user))?;
+        Ok(true)
+    })();
+
+    match result {
+        Ok(true) => events(Line::new("uploaded").arg(id.to_string()).arg(user)),
+        Ok(false) => {}
+        Err(e) => events(Line::new("failed").arg(id.to_string()).arg(format!("upload to {} stopped: {}", user, e))),
+    }
+}
+
+// The hash names the part file, so nothing but hex gets near the path
+fn is_sha256(text: &str) -> bool {
+    text.len() == 64 && text.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("nameless-files-{}-{}", name, process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    // What handle() keeps from an "offer" frame
+    fn offered(files: &mut Files, id: u64, name: &str, data: &[u8]) {
+        let sha256 = Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect();
+        files.incoming.insert(id, Incoming { name: name.to_string(), size: data.len() as u64, sha256, received: 0, accepted: false });
+    }
+
+    fn chunk(data: &[u8]) -> String {
+        STANDARD.encode(data)
+    }
+
+    #[test]
+    fn downloads_resume_and_are_checked() {
+        let dir = temp_dir("resume");
+        let data = b"the whole file, in two parts";
+        let size = data.len() as u64;
+        let mut files = Files::new(dir.clone());
+        offered(&mut files, 1, "notes.txt", data);
+        assert!(save_chunk(&mut files, 1, "0", &chunk(&data[..8])).is_err()); // not accepted yet
+        assert_eq!(files.accept(1).unwrap(), Line::new("accept").arg("1").arg("0"));
+        assert_eq!(save_chunk(&mut files, 1, "0", &chunk(&data[..8])), Ok((8, size)));
+        assert!(save_chunk(&mut files, 1, "0", &chunk(&data[..8])).is_err()); // out of order
+        assert!(save_chunk(&mut files, 1, "8", "not base64!").is_err());
+
+        // The sender left, offering it again picks up after what was saved
+        files.incoming.remove(&1);
+        offered(&mut files, 2, "notes.txt", data);
+        assert_eq!(files.accept(2).unwrap(), Line::new("accept").arg("2").arg("8"));
+        assert_eq!(save_chunk(&mut files, 2, "8", &chunk(&data[8..])), Ok((size, size)));
+        assert!(save_chunk(&mut files, 2, &size.to_string(), &chunk(b"x")).is_err()); // more than offered
+
+        let part = files.part_path(2).unwrap();
+        let incoming = files.incoming.remove(&2).unwrap();
+        let saved = finish_download(&dir, &part, &incoming).unwrap();
+        assert_eq!(saved, dir.join("notes.txt"));
+        assert_eq!(fs::read(&saved).unwrap(), data);
+        assert!(!part.exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn one_download_per_file_at_a_time() {
+        let dir = temp_dir("twice");
+        let data = b"offered twice";
+        let mut files = Files::new(dir.clone());
+        offered(&mut files, 1, "a.txt", data);
+        offered(&mut files, 2, "b.txt", data);
+        files.accept(1).unwrap();
+        assert!(files.accept(2).is_err());
+        assert!(!files.incoming[&2].accepted);
+        // Once the first is gone the second picks up after it
+        save_chunk(&mut files, 1, "0", &chunk(&data[..4])).unwrap();
+        files.incoming.remove(&1);
+        assert_eq!(files.accept(2).unwrap(), Line::new("accept").arg("2").arg("4"));
+
+        assert!(is_sha256(&files.incoming[&2].sha256));
+        assert!(!is_sha256("../../../home/user/.bashrc"));
+        assert!(!is_sha256(&"a".repeat(63)));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn damaged_downloads_are_thrown_away() {
+        let dir = temp_dir("damaged");
+        let mut files = Files::new(dir.clone());
+        offered(&mut files, 1, "notes.txt", b"what was offered");
+        files.accept(1).unwrap();
+        save_chunk(&mut files, 1, "0", &chunk(b"what arrived....")).unwrap();
+
+        let part = files.part_path(1).unwrap();
+        let incoming = files.incoming.remove(&1).unwrap();
+        assert!(finish_download(&dir, &part, &incoming).is_err());
+        assert!(!part.exists() && !dir.join("notes.txt").exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn downloads_never_overwrite_or_leave_the_directory() {
+        let dir = temp_dir("names");
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(free_path(&dir, "../../etc/passwd"), dir.join("passwd"));
+        for name in ["archive.tar.gz", ".bashrc"] {
+            fs::write(dir.join(name), "taken").unwrap();
+        }
+        assert_eq!(free_path(&dir, "archive.tar.gz"), dir.join("archive.tar (1).gz"));
+        fs::write(dir.join("archive.tar (1).gz"), "taken").unwrap();
+        assert_eq!(free_path(&dir, "archive.tar.gz"), dir.join("archive.tar (2).gz"));
+        assert_eq!(free_path(&dir, ".bashrc"), dir.join(".bashrc (1)"));
+        assert_eq!(free_path(&dir, ".."), dir.join("download"));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,387 @@
+// The client proper. The rust_client binary drives it from stdin and stdout, the GTK
+// UI loads it as a library and talks to it through bridge.rs.
+
+mod auth;
+mod bridge;
+mod commands;
+mod files;
+mod markdown;
+mod quotes;
+mod search;
+
+use std::{
+    env,
+    io::{self, BufRead, BufReader, Write, Read},
+    net::{Shutdown, TcpStream}, //sockets too
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, mpsc},
+    thread,
+};
+use std::sync::{Arc, Mutex};
+use commands::Command;
+use files::{Files, SharedFiles, SharedWriter};
+use nameless_proto::{
+    auth::Intro,
+    frame::{self, Line},
+    lobby::{LOBBY_PORT, Listing, Request},
+};
+use quotes::{QuoteCache, SharedQuotes};
+use search::SearchQuery;
+
+const INVITE_VAR: &str = "NAMELESS_INVITE"; // invite code, takes us to its server and room
+const LOBBY_VAR: &str = "NAMELESS_LOBBY"; // "<host>:<port>" of the lobby, localhost otherwise
+const LOBBY_REPLY_MAX: u64 = 1024;
+
+// Where the events for the UI go, one whole line each, see to_event for the list
+pub type Events = Arc<dyn Fn(Line) + Send + Sync>;
+
+// Where the lobby is and where our key is kept, from the environment unless a test says
+struct Places {
+    lobby: String,
+    identity: PathBuf,
+}
+
+impl Places {
+    fn from_env() -> Places {
+        Places {
+            lobby: env::var(LOBBY_VAR).unwrap_or_else(|_| format!("localhost:{}", LOBBY_PORT)),
+            identity: env::var_os(auth::IDENTITY_VAR).map_or_else(|| PathBuf::from(auth::DEFAULT_IDENTITY_FILE), PathBuf::from),
+        }
+    }
+}
+
+// Joins a server as `username` and runs until the input ends or says /quit. Every input
+// line is a message or a command, and also answers a "password" event when one was sent.
+pub fn run(username: &str, input: impl Iterator<Item = io::Result<String>>, events: Events) -> io::Result<()> {
+    run_from(username, input, events, Places::from_env())
+}
+
+fn run_from(username: &str, mut input: impl Iterator<Item = io::Result<String>>, events: Events, places: Places) -> io::Result<()> {
+    let username = username.trim().to_string();
+    eprintln!("Username received: '{}'", username);
+
+    // Who we are to servers, a server owner names its owner by this key
+    let identity = auth::load_identity(&places.identity)?;
+    eprintln!("Identity key: {}", hex::encode(identity.verifying_key().as_bytes()));
+
+    // // Connect to the lobby
+    let mut lobby_stream = TcpStream::connect(places.lobby.trim())?;
+    // let mut lobby_stream = TcpStream::connect("5.tcp.eu.ngrok.io:18940")?;
+    let code = env::var(INVITE_VAR).ok().map(|code| code.trim().to_string());
+    writeln!(lobby_stream, "{}", Request::Client { name: username.clone(), code })?;
+    lobby_stream.flush()?; // Ensure data is sent
+
+    // Read server IP from lobby, the whole line even when it comes in pieces
+    let mut reply = String::new();
+    BufReader::new((&lobby_stream).take(LOBBY_REPLY_MAX)).read_line(&mut reply)?;
+    // The lobby flags servers that want a password and adds the room when we came with
+    // an invite. Anything but a listing is the lobby saying no.
+    let Some(Listing { addr: target_ip, locked, room: invited_room }) = Listing::parse(&reply) else {
+        events(Line::new("error").arg(reply.trim()));
+        return Ok(());
+    };
+
+    // The password comes from the environment or, when a server wants one, from the UI
+    let mut password = env::var(auth::PASSWORD_VAR).ok();
+    if locked && password.is_none() {
+        password = Some(ask_password(&mut input, &events, &target_ip)?);
+    }
+
+    // let target_ip = "5.tcp.eu.ngrok.io:18940";
+
+    // Connect directly to the chosen server
+    let mut server_stream = TcpStream::connect(&target_ip)?;
+    writeln!(server_stream, "{}", Intro { name: username.clone(), since: None })?;
+    server_stream.flush()?;
+
+    let key_bytes = match auth::handshake(&mut server_stream, &identity, || match password.take() {
+        Some(password) => Ok(password),
+        None => ask_password(&mut input, &events, &target_ip),
+    }) {
+        Ok(key) => key,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            events(Line::new("error").arg(format!("could not join {}: {}", target_ip, e)));
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let aes_cipher = nameless_proto::auth::cipher(&key_bytes);
+    eprintln!("Handshake done, cipher initialized.");
+
+    let aes_cipher = Arc::new(aes_cipher);
+
+
+    let read_stream = server_stream.try_clone()?;
+    let write_stream: SharedWriter = Arc::new(Mutex::new(server_stream.try_clone()?));
+    let write_stream_reader = Arc::clone(&write_stream);
+
+    // Channel for UI input
+    let (tx, rx) = mpsc::channel::<Line>();
+
+    let aes_cipher_reader = Arc::clone(&aes_cipher);
+    let aes_cipher_writer = Arc::clone(&aes_cipher);
+
+    // The name we go by, the server may rename us after /nick
+    let current_name = Arc::new(Mutex::new(username.clone()));
+    let current_name_reader = Arc::clone(&current_name);
+
+    // What we've seen so far, for quoting the message a reply answers
+    let quotes: SharedQuotes = Arc::new(Mutex::new(QuoteCache::new()));
+    let quotes_reader = Arc::clone(&quotes);
+
+    // Offers, downloads and uploads, see files.rs
+    let download_dir = env::var_os(files::DOWNLOAD_DIR_VAR).map_or_else(|| PathBuf::from(files::DEFAULT_DOWNLOAD_DIR), PathBuf::from);
+    let files: SharedFiles = Arc::new(Mutex::new(Files::new(download_dir)));
+    let files_reader = Arc::clone(&files);
+
+    // Read receipts go out for every live message we print, unless /receipts off
+    let send_receipts = Arc::new(AtomicBool::new(true));
+    let send_receipts_reader = Arc::clone(&send_receipts);
+
+    // Where the last search left off, "more=<id>" on its results, for /more
+    let search_more: Arc<Mutex<Option<u64>>> = Arc::new(Mutex::new(None));
+    let search_more_reader = Arc::clone(&search_more);
+    let receipt_tx = tx.clone();
+    let events_reader = Arc::clone(&events);
+    let events_writer = Arc::clone(&events);
+
+
+    // Thread to read from server and hand the events to the UI
+    thread::spawn(move || {
+        let cipher = aes_cipher_reader;
+        let events = events_reader;
+        let mut reader = BufReader::new(read_stream);
+
+        loop {
+            let plaintext = match frame::read_frame(&mut reader, &cipher) {
+                Ok(p) => p,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    eprintln!("Dropping frame: {}", e);
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Error reading frame: {}", e);
+                    break;
+                }
+            };
+            let line = Line::parse(&plaintext);
+            if let Some(line) = &line
+                && matches!(line.cmd.as_str(), "offer" | "offered" | "accept" | "reject" | "cancel" | "chunk" | "done")
+            {
+                if let Some(event) = files::handle(&files_reader, &write_stream_reader, &cipher, &events, line.clone()) {
+                    events(event);
+                }
+                continue;
+            }
+            if let Some(line) = &line
+                && line.cmd == "nick"
+            {
+                let mut name = current_name_reader.lock().unwrap();
+                if let (Some(old), Some(new)) = (line.get(0), line.get(1))
+                    && old == *name
+                {
+                    *name = new.to_string();
+                    eprintln!("Now known as '{}'", name);
+                }
+            }
+            if let Some(line) = &line
+                && line.cmd == "results"
+            {
+                *search_more_reader.lock().unwrap() = line.get_tag("more").and_then(|id| id.parse().ok());
+            }
+            let receipt = line.as_ref().and_then(read_receipt_for);
+            let line = line.map(|line| {
+                let mut quotes = quotes_reader.lock().unwrap();
+                let line = quotes.annotate(line);
+                quotes.observe(&line);
+                line
+            });
+            match line.and_then(to_event).map(markdown::format_event) {
+                Some(event) => events(event),
+                None => eprintln!("Unrecognised frame from server: {}", plaintext),
+            }
+            if let Some(receipt) = receipt
+                && send_receipts_reader.load(Ordering::Relaxed)
+            {
+                receipt_tx.send(receipt).ok();
+            }
+        }
+    });
+
+    // Thread to write messages to server
+    thread::spawn(move || {
+        let cipher = aes_cipher_writer;
+        let events = events_writer;
+
+        for line in rx {
+            if frame::write_frame(&mut *write_stream.lock().unwrap(), &cipher, &line).is_err() {
+                eprintln!("Failed to send frame to server");
+                break;
+            }
+            if let Some(local) = line.get_tag("local") {
+                events(Line::new("sent").arg(local));
+            }
+        }
+    });
+
+    // Our own numbering for outgoing messages, the server acks each with its real id
+    let mut next_local_id: u64 = 1;
+    let mut last_search: Option<SearchQuery> = None;
+
+    if let Some(room) = invited_room {
+        tx.send(Line::new("join").arg(room)).ok();
+    }
+
+    for line in input {
+        let msg = line?;
+        match commands::parse(&msg) {
+            Ok(Command::Send(mut frame)) => {
+                if let Some(echo) = own_message(&frame, &current_name.lock().unwrap()) {
+                    let local = next_local_id.to_string();
+                    let mut quotes = quotes.lock().unwrap();
+                    if frame.cmd != "dm" {
+                        quotes.sent(&local, &echo.args[0], &echo.args[1]);
+                    }
+                    events(markdown::format_event(quotes.annotate(echo.tag("local", &local))));
+                    frame = frame.tag("local", local);
+                    next_local_id += 1;
+                }
+                if tx.send(frame).is_err() {
+                    break;
+                }
+            }
+            Ok(Command::Help) => {
+                for help in commands::HELP {
+                    events(Line::new("system").arg(*help));
+                }
+            }
+            Ok(Command::Receipts(on)) => send_receipts.store(on, Ordering::Relaxed),
+            Ok(Command::SendFile { to, path }) => {
+                let local = next_local_id.to_string();
+                match files::offer(&files, &local, Path::new(&path), to.as_deref()) {
+                    Ok(offer) => {
+                        next_local_id += 1;
+                        if tx.send(offer.tag("local", local)).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => events(Line::new("error").arg(format!("can't send {}: {}", path, e))),
+                }
+            }
+            Ok(Command::Accept(id)) => match files.lock().unwrap().accept(id) {
+                Ok(accept) => {
+                    if tx.send(accept).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => events(Line::new("error").arg(e)),
+            },
+            Ok(Command::Reject(id)) => match files.lock().unwrap().reject(id) {
+                Ok(reject) => {
+                    if tx.send(reject).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => events(Line::new("error").arg(e)),
+            },
+            Ok(Command::Search(query)) => {
+                *search_more.lock().unwrap() = None;
+                if tx.send(query.to_line()).is_err() {
+                    break;
+                }
+                last_search = Some(query);
+            }
+            Ok(Command::More) => {
+                let more = *search_more.lock().unwrap();
+                match (&last_search, more) {
+                    (Some(query), Some(id)) => {
+                        if tx.send(query.page_before(id).to_line()).is_err() {
+                            break;
+                        }
+                    }
+                    (Some(_), None) => events(Line::new("system").arg("no more results")),
+                    (None, _) => events(Line::new("error").arg("nothing searched yet, try /search")),
+                }
+            }
+            Ok(Command::Downloads(dir)) => files.lock().unwrap().set_download_dir(PathBuf::from(dir)),
+            Ok(Command::Quit) => break,
+            Err(e) => events(Line::new("error").arg(e)),
+        }
+    }
+
+    server_stream.shutdown(Shutdown::Both).ok();
+    Ok(())
+}
+
+// Asks the UI with a "password :<text>" event, the answer is the next input line
+fn ask_password(input: &mut impl Iterator<Item = io::Result<String>>, events: &Events, server: &str) -> io::Result<String> {
+    events(Line::new("password").arg(format!("{} needs a password", server)));
+    let password = input.next().unwrap_or_else(|| Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no password given")))?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// Maps a server frame onto the event line for the UI. Tags are passed on untouched:
+// whatever the server relays carries "ts=<unix seconds, UTC>" so every participant shows
+// the same time, events without it are ours and happen now. Room messages also have
+// "id=..", plus "replay" when they come from history and "queued" for messages/mentions
+// that waited while we were offline:
+// Text in msg, action, private and replace comes without its markdown markers, styled
+// parts are listed in "spans=<kind>:<start>:<end>[:<url>] .." (kinds: bold, italic, code,
+// codeblock, link), offsets in characters of the text, see markdown.rs:
+//   msg <from> :<text>      room message. Replies also carry "in_reply_to=<id>;thread=<root>"
+//                           and, when we've seen the parent, "quote_from=<author>;quote=<excerpt>".
+//                           "mentions=<user>,.." lists who it @mentions, "highlight" if that's us
+//   action <from> :<text>   /me in the room
+//   private <from> :<text>  direct message, with an "id=.." like room messages
+// Our own msg, action and private go to the UI as well, as soon as they're typed, tagged
+// "local=<n>" instead of "id" ("to=<user>" on private), the events below follow them up:
+//   room :<name>            the room we're now in
+//   roster <user> <user>..  everyone online, sent on connect and for /who
+//   presence <user> online|offline
+//   nick <old> <new>        someone (maybe us) changed name
+//   sent <local>            our message <local> left for the server ("@local=.." on our side)
+//   delivered <local> <id>  the server stored and relayed it as <id>
+//   read <id> <user>        <user> has read our message <id>
+//   replace <id> :<text>    message <id> was edited ("@edited=<ts>"), show the new text
+//   retract <id>            message <id> was deleted, take it off the screen
+//   reaction <id> <emoji> <count>  new total for one emoji, "@by=<user>" did it.
+//                           replayed messages carry "@reactions=<emoji>:<count>,.." instead
+//   typing <user> start|stop
+//   thread <root> <count>   answer to /thread, the <count> messages follow tagged "replay"
+//   results <count> :<words>  answer to /search, "more=<id>" when /more has older ones.
+//                           The <count> matches follow, newest first, as
+//   hit <from> :<excerpt>   "id=..;ts=..;room=.." of the message found
+//   offer, progress, received ...  file transfers, listed in files.rs
+//   system :<text>          notices from the server or local ones (help output etc.),
+//                           "motd" on the lines of the message of the day
+//   error :<text>           something we did was refused
+//   password :<text>        the server is locked, the next input line is the password
+fn to_event(line: Line) -> Option<Line> {
+    match line.cmd.as_str() {
+        "msg" | "action" | "room" | "roster" | "presence" | "nick" | "typing" | "read" | "reaction" | "thread" | "results" | "hit" | "system" | "error" => Some(line),
+        "ack" => Some(Line { cmd: "delivered".to_string(), ..line }),
+        "edit" => Some(Line { cmd: "replace".to_string(), ..line }),
+        "delete" => Some(Line { cmd: "retract".to_string(), ..line }),
+        "dm" => Some(Line { cmd: "private".to_string(), ..line }),
+        _ => None,
+    }
+}
+
+// Our say, me or dm as the event the UI shows for it, None for other frames
+fn own_message(frame: &Line, name: &str) -> Option<Line> {
+    let echo = |cmd: &str, text: &str| Line { tags: frame.tags.clone(), cmd: cmd.to_string(), args: vec![name.to_string(), text.to_string()] };
+    match frame.cmd.as_str() {
+        "say" => Some(echo("msg", frame.get(0)?)),
+        "me" => Some(echo("action", frame.get(0)?)),
+        "dm" => Some(echo("private", frame.get(1)?).tag("to", frame.get(0)?)),
+        _ => None,
+    }
+}
+
+// Live messages from others, room or direct, get a "read <id>", history replays don't
+fn read_receipt_for(line: &Line) -> Option<Line> {
+    if !matches!(line.cmd.as_str(), "msg" | "action" | "dm") || line.get_tag("replay").is_some() {
+        return None;
+    }
+    line.get_tag("id").map(|id| Line::new("read").arg(id))
+}
@@ -0,0 +1,363 @@
+use nameless_proto::frame::Line;
+
+// Longer texts are shown as they are, finding closing markers is quadratic at worst
+const MAX_FORMATTED_CHARS: usize = 4000;
+const FENCE: [char; 3] = ['`'; 3];
+const ESCAPABLE: &[char] = &['\\', '*', '_', '`', '[', ']', '(', ')'];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Style {
+    Bold,         // **text** or __text__
+    Italic,       // *text* or _text_
+    Code,         // `text`
+    CodeBlock,    // ```text```
+    Link(String), // [text](url)
+}
+
+// Offsets count characters (not bytes) of the text with the markers taken out, which is
+// what GtkTextIter offsets count too. Spans nest: a span comes before the ones inside it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize, // exclusive
+    pub style: Style,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Formatted {
+    pub text: String,
+    pub spans: Vec<Span>,
+}
+
+pub fn parse(input: &str) -> Formatted {
+    let chars: Vec<char> = input.chars().collect();
+    if chars.len() > MAX_FORMATTED_CHARS {
+        return Formatted { text: input.to_string(), spans: Vec::new() };
+    }
+    let mut parser = Parser { text: String::with_capacity(input.len()), len: 0, spans: Vec::new() };
+    parser.inline(&chars);
+    Formatted { text: parser.text, spans: parser.spans }
+}
+
+// "bold:0:5 link:6:10:https://example.com", what goes into an event's "spans" tag
+pub fn encode_spans(spans: &[Span]) -> String {
+    let encoded: Vec<String> = spans
+        .iter()
+        .map(|span| {
+            let kind = match &span.style {
+                Style::Bold => "bold",
+                Style::Italic => "italic",
+                Style::Code => "code",
+                Style::CodeBlock => "codeblock",
+                Style::Link(url) => return format!("link:{}:{}:{}", span.start, span.end, url),
+            };
+            format!("{}:{}:{}", kind, span.start, span.end)
+        })
+        .collect();
+    encoded.join(" ")
+}
+
+// Text events get their markers taken out and a "spans" tag when anything is styled
+pub fn format_event(mut line: Line) -> Line {
+    if !matches!(line.cmd.as_str(), "msg" | "action" | "private" | "replace") || line.args.len() < 2 {
+        return line;
+    }
+    let Some(last) = line.args.last_mut() else {
+        return line;
+    };
+    let formatted = parse(last);
+    *last = formatted.text;
+    if formatted.spans.is_empty() { line } else { line.tag("spans", encode_spans(&formatted.spans)) }
+}
+
+struct Parser {
+    text: String,
+    len: usize, // chars in text so far
+    spans: Vec<Span>,
+}
+
+impl Parser {
+    fn push(&mut self, c: char) {
+        self.text.push(c);
+        self.len += 1;
+    }
+
+    fn push_all(&mut self, chars: &[char]) {
+        for &c in chars {
+            self.push(c);
+        }
+    }
+
+    // Puts the span in the list before filling it, so it lands ahead of the spans inside
+    fn styled(&mut self, style: Style, fill: impl FnOnce(&mut Self)) {
+        let index = self.spans.len();
+        let start = self.len;
+        self.spans.push(Span { start, end: start, style });
+        fill(self);
+        self.spans[index].end = self.len;
+    }
+
+    fn inline(&mut self, chars: &[char]) {
+        let mut i = 0;
+        while i < chars.len() {
+            let rest = &chars[i..];
+            let before = if i > 0 { Some(chars[i - 1]) } else { None };
+            match rest[0] {
+                '\\' if rest.len() > 1 && ESCAPABLE.contains(&rest[1]) => {
+                    self.push(rest[1]);
+                    i += 2;
+                }
+                '`' if rest.starts_with(&FENCE) => match code_block(rest) {
+                    Some((body, used)) => {
+                        self.styled(Style::CodeBlock, |p| p.push_all(body));
+                        i += used;
+                    }
+                    None => {
+                        self.push_all(&FENCE);
+                        i += FENCE.len();
+                    }
+                },
+                '`' => match find_code_end(rest, 1) {
+                    Some(end) if end > 1 => {
+                        self.styled(Style::Code, |p| p.push_all(&rest[1..end]));
+                        i += end + 1;
+                    }
+                    _ => {
+                        self.push('`');
+                        i += 1;
+                    }
+                },
+                '[' => match link(rest) {
+                    Some((label, url, used)) => {
+                        self.styled(Style::Link(url), |p| p.inline(label));
+                        i += used;
+                    }
+                    None => {
+                        self.push('[');
+                        i += 1;
+                    }
+                },
+                marker @ ('*' | '_') => {
+                    let double = rest.get(1) == Some(&marker);
+                    let width = if double { 2 } else { 1 };
+                    match emphasis(rest, before, marker, width) {
+                        Some(end) => {
+                            let style = if double { Style::Bold } else { Style::Italic };
+                            self.styled(style, |p| p.inline(&rest[width..end]));
+                            i += end + width;
+                        }
+                        None => {
+                            self.push_all(&rest[..width]);
+                            i += width;
+                        }
+                    }
+                }
+                c => {
+                    self.push(c);
+                    i += 1;
+                }
+            }
+        }
+    }
+}
+
+// ```body``` -> (body, chars used). A newline right after the opening fence and one right
+// before the closing fence belong to the fences, not the code.
+fn code_block(rest: &[char]) -> Option<(&[char], usize)> {
+    let close = (FENCE.len()..rest.len()).find(|&j| rest[j..].starts_with(&FENCE))?;
+    let mut body = &rest[FENCE.len()..close];
+    if body.first() == Some(&'\n') {
+        body = &body[1..];
+    }
+    if body.last() == Some(&'\n') {
+        body = &body[..body.len() - 1];
+    }
+    if body.is_empty() {
+        return None;
+    }
+    Some((body, close + FENCE.len()))
+}
+
+// Index of the backtick closing an inline code span, nothing inside is special
+fn find_code_end(rest: &[char], from: usize) -> Option<usize> {
+    (from..rest.len()).find(|&j| rest[j] == '`')
+}
+
+// [label](url) -> (label, url, chars used). The url can't hold whitespace.
+fn link(rest: &[char]) -> Option<(&[char], String, usize)> {
+    let mut j = 1;
+    while j < rest.len() && rest[j] != ']' {
+        j += if rest[j] == '\\' { 2 } else { 1 };
+    }
+    if j >= rest.len() || j == 1 || rest.get(j + 1) != Some(&'(') {
+        return None;
+    }
+    let url_start = j + 2;
+    let url_end = (url_start..rest.len()).find(|&k| rest[k] == ')')?;
+    let url = &rest[url_start..url_end];
+    if url.is_empty() || url.iter().any(|c| c.is_whitespace()) {
+        return None;
+    }
+    Some((&rest[1..j], url.iter().collect(), url_end + 1))
+}
+
+// Where the matching closing marker starts, if the run opening at rest[0] is emphasis at all.
+// Openers must be followed by a non-space and closers preceded by one, underscores also
+// have to sit at word boundaries so snake_case stays as it is.
+fn emphasis(rest: &[char], before: Option<char>, marker: char, width: usize) -> Option<usize> {
+    let first = *rest.get(width)?;
+    if first.is_whitespace() || first == marker {
+        return None;
+    }
+    if marker == '_' && before.is_some_and(char::is_alphanumeric) {
+        return None;
+    }
+    let mut j = width;
+    while j < rest.len() {
+        match rest[j] {
+            '\\' => j += 2,
+            '`' => j = find_code_end(rest, j + 1).map_or(j + 1, |end| end + 1),
+            c if c == marker => {
+                let run = rest[j..].iter().take_while(|&&c| c == marker).count();
+                let closes = run >= width
+                    && !rest[j - 1].is_whitespace()
+                    && (marker != '_' || !rest.get(j + width).is_some_and(|c| c.is_alphanumeric()));
+                // a single marker can't close on a double, that belongs to a bold inside.
+                // A longer run closes with its last markers, the first ones close what's inside.
+                if closes && (width == 2 || run != 2) {
+                    return Some(j + run - width);
+                }
+                j += run;
+            }
+            _ => j += 1,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: usize, end: usize, style: Style) -> Span {
+        Span { start, end, style }
+    }
+
+    fn formatted(text: &str, spans: Vec<Span>) -> Formatted {
+        Formatted { text: text.to_string(), spans }
+    }
+
+    #[test]
+    fn plain_text_is_left_alone() {
+        assert_eq!(parse("just words, 2 * 3 = 6"), formatted("just words, 2 * 3 = 6", vec![]));
+        assert_eq!(parse(""), formatted("", vec![]));
+    }
+
+    #[test]
+    fn bold_and_italic() {
+        assert_eq!(parse("a **big** deal"), formatted("a big deal", vec![span(2, 5, Style::Bold)]));
+        assert_eq!(parse("__big__"), formatted("big", vec![span(0, 3, Style::Bold)]));
+        assert_eq!(parse("so *very* nice"), formatted("so very nice", vec![span(3, 7, Style::Italic)]));
+        assert_eq!(parse("_quiet_ please"), formatted("quiet please", vec![span(0, 5, Style::Italic)]));
+    }
+
+    #[test]
+    fn emphasis_nests_outer_span_first() {
+        assert_eq!(
+            parse("**bold *and italic***"),
+            formatted("bold and italic", vec![span(0, 15, Style::Bold), span(5, 15, Style::Italic)])
+        );
+        assert_eq!(
+            parse("*it **is** fine*"),
+            formatted("it is fine", vec![span(0, 10, Style::Italic), span(3, 5, Style::Bold)])
+        );
+    }
+
+    #[test]
+    fn markers_that_dont_open_or_close_stay_literal() {
+        assert_eq!(parse("a * b * c"), formatted("a * b * c", vec![]));
+        assert_eq!(parse("**unclosed"), formatted("**unclosed", vec![]));
+        assert_eq!(parse("*a *"), formatted("*a *", vec![]));
+        assert_eq!(parse("****"), formatted("****", vec![]));
+        assert_eq!(parse("`"), formatted("`", vec![]));
+        assert_eq!(parse("``"), formatted("``", vec![]));
+    }
+
+    #[test]
+    fn underscores_inside_words_are_not_emphasis() {
+        assert_eq!(parse("call snake_case_name now"), formatted("call snake_case_name now", vec![]));
+        assert_eq!(parse("_a_b"), formatted("_a_b", vec![]));
+    }
+
+    #[test]
+    fn inline_code_is_literal() {
+        assert_eq!(parse("run `cargo *test*` now"), formatted("run cargo *test* now", vec![span(4, 16, Style::Code)]));
+        assert_eq!(parse("**see `a**b`**"), formatted("see a**b", vec![span(0, 8, Style::Bold), span(4, 8, Style::Code)]));
+    }
+
+    #[test]
+    fn code_blocks() {
+        assert_eq!(parse("```let x = **1**;```"), formatted("let x = **1**;", vec![span(0, 14, Style::CodeBlock)]));
+        assert_eq!(parse("look:```\nfn main() {}\n```"), formatted("look:fn main() {}", vec![span(5, 17, Style::CodeBlock)]));
+        assert_eq!(parse("```unclosed"), formatted("```unclosed", vec![]));
+        assert_eq!(parse("``````"), formatted("``````", vec![]));
+    }
+
+    #[test]
+    fn links() {
+        let url = "https://example.com/a_b*c";
+        assert_eq!(
+            parse("see [the docs](https://example.com/a_b*c)!"),
+            formatted("see the docs!", vec![span(4, 12, Style::Link(url.to_string()))])
+        );
+        assert_eq!(
+            parse("[**bold** link](http://x)"),
+            formatted("bold link", vec![span(0, 9, Style::Link("http://x".to_string())), span(0, 4, Style::Bold)])
+        );
+    }
+
+    #[test]
+    fn broken_links_stay_literal() {
+        assert_eq!(parse("[text] (http://x)"), formatted("[text] (http://x)", vec![]));
+        assert_eq!(parse("[text](not a url)"), formatted("[text](not a url)", vec![]));
+        assert_eq!(parse("[](http://x)"), formatted("[](http://x)", vec![]));
+        assert_eq!(parse("[text](http://x"), formatted("[text](http://x", vec![]));
+    }
+
+    #[test]
+    fn backslash_escapes_markers() {
+        assert_eq!(parse(r"\*not italic\*"), formatted("*not italic*", vec![]));
+        assert_eq!(parse(r"**a \** b**"), formatted("a ** b", vec![span(0, 6, Style::Bold)]));
+        assert_eq!(parse(r"\[x](y)"), formatted("[x](y)", vec![]));
+        assert_eq!(parse(r"c:\path\n"), formatted(r"c:\path\n", vec![]));
+    }
+
+    #[test]
+    fn offsets_count_characters_not_bytes() {
+        assert_eq!(parse("héllo **wörld** 👋"), formatted("héllo wörld 👋", vec![span(6, 11, Style::Bold)]));
+        assert_eq!(parse("👋 *hi*"), formatted("👋 hi", vec![span(2, 4, Style::Italic)]));
+    }
+
+    #[test]
+    fn very_long_text_is_not_formatted() {
+        let long = format!("**{}**", "a".repeat(MAX_FORMATTED_CHARS));
+        assert_eq!(parse(&long), formatted(&long, vec![]));
+    }
+
+    #[test]
+    fn spans_encode_for_the_event_tag() {
+        let spans = vec![span(0, 4, Style::Bold), span(5, 9, Style::Link("http://x/a:b".to_string())), span(10, 12, Style::CodeBlock)];
+        assert_eq!(encode_spans(&spans), "bold:0:4 link:5:9:http://x/a:b codeblock:10:12");
+        assert_eq!(encode_spans(&[]), "");
+    }
+
+    #[test]
+    fn text_events_get_formatted() {
+        let msg = Line::new("msg").tag("id", "3").arg("bob").arg("hi **all**");
+        assert_eq!(format_event(msg), Line::new("msg").tag("id", "3").arg("bob").arg("hi all").tag("spans", "bold:3:6"));
+        let escaped = Line::new("replace").arg("3").arg(r"\*");
+        assert_eq!(format_event(escaped), Line::new("replace").arg("3").arg("*"));
+        let system = Line::new("system").arg("**not** a message");
+        assert_eq!(format_event(system.clone()), system);
+    }
+}
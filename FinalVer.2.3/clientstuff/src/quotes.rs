@@ -0,0 +1,96 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+use nameless_proto::frame::Line;
+use crate::markdown;
+
+pub type SharedQuotes = Arc<Mutex<QuoteCache>>;
+
+const QUOTE_CACHE_SIZE: usize = 1000; // messages remembered for quoting, oldest go first
+const EXCERPT_CHARS: usize = 60;
+
+// Room messages we've seen, so a reply can show who and what it answers
+// without asking the server. Only the author and the text are kept.
+// The server doesn't echo our own messages, they're filed under their id once acked.
+pub struct QuoteCache {
+    messages: HashMap<u64, (String, String)>,
+    order: VecDeque<u64>,
+    unacked: HashMap<String, (String, String)>, // local id -> (us, text)
+}
+
+impl QuoteCache {
+    pub fn new() -> Self {
+        QuoteCache { messages: HashMap::new(), order: VecDeque::new(), unacked: HashMap::new() }
+    }
+
+    pub fn sent(&mut self, local: &str, from: &str, text: &str) {
+        if self.unacked.len() >= QUOTE_CACHE_SIZE {
+            self.unacked.clear(); // acks that never came, e.g. refused replies
+        }
+        self.unacked.insert(local.to_string(), (from.to_string(), text.to_string()));
+    }
+
+    // Keeps track of msg/action frames, acks, edits and deletes as they arrive
+    pub fn observe(&mut self, line: &Line) {
+        if line.cmd == "ack"
+            && let (Some(local), Some(id)) = (line.get(0), line.get(1).and_then(|id| id.parse().ok()))
+            && let Some(message) = self.unacked.remove(local)
+        {
+            self.remember(id, message);
+            return;
+        }
+        let id = match line.cmd.as_str() {
+            "msg" | "action" => line.get_tag("id"),
+            "edit" | "delete" => line.get(0),
+            _ => None,
+        };
+        let Some(id) = id.and_then(|id| id.parse::<u64>().ok()) else {
+            return;
+        };
+        match (line.cmd.as_str(), line.get(0), line.get(1)) {
+            ("msg" | "action", Some(from), Some(text)) => self.remember(id, (from.to_string(), text.to_string())),
+            ("edit", _, Some(text)) => {
+                if let Some((_, old)) = self.messages.get_mut(&id) {
+                    *old = text.to_string();
+                }
+            }
+            ("delete", _, _) => {
+                self.messages.remove(&id);
+            }
+            _ => {}
+        }
+    }
+
+    fn remember(&mut self, id: u64, message: (String, String)) {
+        if self.messages.insert(id, message).is_none() {
+            self.order.push_back(id);
+        }
+        while self.order.len() > QUOTE_CACHE_SIZE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.messages.remove(&oldest);
+            }
+        }
+    }
+
+    // Adds "quote_from=<author>;quote=<excerpt>" to a reply whose parent we know
+    pub fn annotate(&self, line: Line) -> Line {
+        let parent = line.get_tag("in_reply_to").and_then(|id| id.parse::<u64>().ok());
+        match parent.and_then(|id| self.messages.get(&id)) {
+            Some((from, text)) => line.tag("quote_from", from).tag("quote", excerpt(text)),
+            None => line,
+        }
+    }
+}
+
+// First line of the text without its markdown, cut to EXCERPT_CHARS with "…" when
+// something was left out
+fn excerpt(text: &str) -> String {
+    let text = &markdown::parse(text).text;
+    let first_line = text.lines().next().unwrap_or_default();
+    let mut short: String = first_line.chars().take(EXCERPT_CHARS).collect();
+    if short.len() < text.len() {
+        short.push('…');
+    }
+    short
+}
@@ -0,0 +1,151 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use nameless_proto::frame::Line;
+
+pub const USAGE: &str = "usage: /search [in:<room>] [from:<user>] [since:<date|7d>] [until:<date|7d>] <words>";
+
+const DAY: u64 = 24 * 60 * 60;
+
+// A history search as the server takes it, /search parses into one.
+// Dates are unix seconds: `since` inclusive, `until` exclusive.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchQuery {
+    pub words: String,
+    pub room: Option<String>,
+    pub from: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub before: Option<u64>, // paging, only messages older than this id
+    pub limit: Option<u32>,
+}
+
+impl SearchQuery {
+    // "in:lounge from:bob since:2026-01-01 until:7d deploy friday", filters anywhere in the line.
+    // Dates are YYYY-MM-DD (UTC, "until" counts the whole day) or 30m/2h/7d ago.
+    pub fn parse(input: &str) -> Result<SearchQuery, String> {
+        let mut query = SearchQuery::default();
+        let mut words = Vec::new();
+        for word in input.split_whitespace() {
+            match word.split_once(':') {
+                Some(("in", room)) if !room.is_empty() => query.room = Some(room.to_string()),
+                Some(("from", user)) if !user.is_empty() => query.from = Some(user.to_string()),
+                Some(("since", date)) => query.since = Some(parse_date(date, false).ok_or(USAGE)?),
+                Some(("until", date)) => query.until = Some(parse_date(date, true).ok_or(USAGE)?),
+                _ => words.push(word),
+            }
+        }
+        query.words = words.join(" ");
+        if query.words.is_empty() && query.from.is_none() {
+            return Err(USAGE.to_string());
+        }
+        Ok(query)
+    }
+
+    // The next page, given the "more" id from the last results
+    pub fn page_before(&self, id: u64) -> SearchQuery {
+        SearchQuery { before: Some(id), ..self.clone() }
+    }
+
+    pub fn to_line(&self) -> Line {
+        let mut line = Line::new("search");
+        if let Some(room) = &self.room {
+            line = line.tag("room", room);
+        }
+        if let Some(from) = &self.from {
+            line = line.tag("from", from);
+        }
+        for (key, value) in [("since", self.since), ("until", self.until), ("before", self.before), ("limit", self.limit.map(u64::from))] {
+            if let Some(value) = value {
+                line = line.tag(key, value);
+            }
+        }
+        line.arg(self.words.as_str())
+    }
+}
+
+fn parse_date(text: &str, end_of_day: bool) -> Option<u64> {
+    if let Some(ago) = parse_ago(text) {
+        return now().checked_sub(ago);
+    }
+    let mut parts = text.splitn(3, '-').map(|part| part.parse::<u64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return None;
+    }
+    let start = days_from_civil(year, month, day) * DAY;
+    Some(if end_of_day { start + DAY } else { start })
+}
+
+// "30m", "2h", "7d"
+fn parse_ago(text: &str) -> Option<u64> {
+    let unit = match text.chars().last()? {
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => DAY,
+        _ => return None,
+    };
+    text[..text.len() - 1].parse::<u64>().ok()?.checked_mul(unit)
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01, Howard Hinnant's days_from_civil for years from 1970 on
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_go_anywhere_and_the_rest_are_words() {
+        let query = SearchQuery::parse("deploy in:ops from:bob friday").unwrap();
+        assert_eq!(query.words, "deploy friday");
+        assert_eq!(query.room.as_deref(), Some("ops"));
+        assert_eq!(query.from.as_deref(), Some("bob"));
+        assert_eq!(SearchQuery::parse("from:bob").unwrap().words, "");
+        assert!(SearchQuery::parse("in:ops").is_err());
+        assert!(SearchQuery::parse("").is_err());
+    }
+
+    #[test]
+    fn dates_are_utc_days_and_until_includes_its_day() {
+        let query = SearchQuery::parse("since:2026-01-01 until:2026-01-31 x").unwrap();
+        assert_eq!(query.since, Some(1767225600));
+        assert_eq!(query.until, Some(1769817600 + DAY));
+        assert_eq!(parse_date("2024-02-29", false), Some(1709164800));
+        assert_eq!(parse_date("1970-01-01", false), Some(0));
+        assert!(SearchQuery::parse("since:2025-02-29 x").is_err());
+        assert!(SearchQuery::parse("since:2026-13-01 x").is_err());
+        assert!(SearchQuery::parse("until:yesterday x").is_err());
+    }
+
+    #[test]
+    fn relative_dates_count_back_from_now() {
+        let since = SearchQuery::parse("since:7d x").unwrap().since.unwrap();
+        assert!(now() - since >= 7 * DAY && now() - since < 7 * DAY + 5);
+    }
+
+    #[test]
+    fn query_becomes_a_tagged_search_frame() {
+        let query = SearchQuery { words: "deploy friday".to_string(), room: Some("ops".to_string()), since: Some(10), ..Default::default() };
+        assert_eq!(query.to_line(), Line::new("search").tag("room", "ops").tag("since", 10).arg("deploy friday"));
+        assert_eq!(query.page_before(42).to_line(), query.to_line().tag("before", 42));
+    }
+}
@@ -18,6 +18,9 @@ gboolean is_maximized = FALSE; //header maximization
 gboolean is_chat_maximized = FALSE; //chat header maximization
 char finalname[126];
 static char last_sender[126];
+GtkWidget *typing_label = NULL; //"x is typing..." under the chat
+static gboolean is_typing = FALSE;
+GtkWidget *transfer_label = NULL; //file transfer progress, next to the typing indicator
 
 typedef struct {
     GtkWidget *entry;
@@ -177,13 +180,27 @@ GtkWidget* create_custom_chatheader(GtkWidget *win) {
 //     gtk_text_view_scroll_mark_onscreen(GTK_TEXT_VIEW(text_view), mark);
 // }
 
-void add_chat_message(GtkWidget *text_view, const char *tname, const char *msg,bool isfromserver) { 
+//named marks "msg-<id>-start"/"msg-<id>-end" around a message's text, moved if the id shows up again
+static void set_message_mark(GtkTextBuffer *buffer, const char *id, const char *which, GtkTextIter *where) {
+    char mark_name[64];
+    snprintf(mark_name, sizeof(mark_name), "msg-%s-%s", id, which);
+    GtkTextMark *mark = gtk_text_buffer_get_mark(buffer, mark_name);
+    if (mark) {
+        gtk_text_buffer_move_mark(buffer, mark, where);
+    } else {
+        gtk_text_buffer_create_mark(buffer, mark_name, where, TRUE);
+    }
+}
+
+//id is the server's message id, "local-<n>" for ours until the server acks it, or NULL.
+//marks around the text let us edit/retract it later, the state after it ("#<id>", "sent", ..) has its own
+//when is the server's timestamp (UTC seconds) so everyone shows the same time, shown in local time
+void add_chat_message(GtkWidget *text_view, const char *tname, const char *msg,bool isfromserver, const char *id, time_t when) { 
     GtkTextBuffer *buffer = gtk_text_view_get_buffer(GTK_TEXT_VIEW(text_view));
     GtkTextIter end;
     gtk_text_buffer_get_end_iter(buffer, &end);
 
-    time_t now = time(NULL);
-    struct tm *local = localtime(&now);
+    struct tm *local = localtime(&when);
     char timestamp[32];
     strftime(timestamp, sizeof(timestamp), "[%H:%M:%S] ", local);
 
@@ -195,6 +212,9 @@ void add_chat_message(GtkWidget *text_view, const char *tname, const char *msg,b
     if (!gtk_text_tag_table_lookup(tag_table, "message")) {
         gtk_text_buffer_create_tag(buffer, "message", "foreground", "white", NULL);
     }//message
+    if (!gtk_text_tag_table_lookup(tag_table, "state")) {
+        gtk_text_buffer_create_tag(buffer, "state", "foreground", "gray", "scale", PANGO_SCALE_SMALL, NULL);
+    }//id and delivery state
     if(isfromserver){
         if (!gtk_text_tag_table_lookup(tag_table, tname)) {
             gtk_text_buffer_create_tag(buffer, tname, "foreground", "red", "weight", PANGO_WEIGHT_BOLD, NULL);
@@ -218,9 +238,21 @@ void add_chat_message(GtkWidget *text_view, const char *tname, const char *msg,b
         gtk_text_buffer_insert_with_tags_by_name(buffer, &end, "        ", -1, "message", NULL);
     }
 
+    if (id) set_message_mark(buffer, id, "start", &end);
+    set_message_mark(buffer, "last", "start", &end); //apply_spans styles the newest message from here
     gtk_text_buffer_insert_with_tags_by_name(buffer, &end, msg, -1, "message", NULL);
-    if(!isfromserver)
-        gtk_text_buffer_insert(buffer, &end, "\n", -1);
+    if (id) {
+        set_message_mark(buffer, id, "end", &end);
+        //the space keeps the state out of the way when the text is replaced
+        gtk_text_buffer_insert_with_tags_by_name(buffer, &end, " ", -1, "message", NULL);
+        char state[64];
+        if (g_str_has_prefix(id, "local-")) g_strlcpy(state, "sending", sizeof(state));
+        else snprintf(state, sizeof(state), "#%s", id);
+        set_message_mark(buffer, id, "state-start", &end);
+        gtk_text_buffer_insert_with_tags_by_name(buffer, &end, state, -1, "state", NULL);
+        set_message_mark(buffer, id, "state-end", &end);
+    }
+    gtk_text_buffer_insert(buffer, &end, "\n", -1); //events come without one
 
     //scroll wheel
     GtkTextMark *mark = gtk_text_buffer_create_mark(buffer, NULL, &end, FALSE);
@@ -228,35 +260,446 @@ void add_chat_message(GtkWidget *text_view, const char *tname, const char *msg,b
 }
 
 
+//swap the text of an earlier message for something else, used for edits and deletes
+void replace_chat_message(GtkWidget *text_view, const char *id, const char *msg) {
+    GtkTextBuffer *buffer = gtk_text_view_get_buffer(GTK_TEXT_VIEW(text_view));
+    char start_name[64], end_name[64];
+    snprintf(start_name, sizeof(start_name), "msg-%s-start", id);
+    snprintf(end_name, sizeof(end_name), "msg-%s-end", id);
+    GtkTextMark *start_mark = gtk_text_buffer_get_mark(buffer, start_name);
+    GtkTextMark *end_mark = gtk_text_buffer_get_mark(buffer, end_name);
+    if (!start_mark || !end_mark) return; //not on screen
+
+    GtkTextIter start, end;
+    gtk_text_buffer_get_iter_at_mark(buffer, &start, start_mark);
+    gtk_text_buffer_get_iter_at_mark(buffer, &end, end_mark);
+    gtk_text_buffer_delete(buffer, &start, &end);
+    gtk_text_buffer_insert_with_tags_by_name(buffer, &start, msg, -1, "message", NULL);
+    gtk_text_buffer_move_mark(buffer, end_mark, &start); //start now sits after the new text
+}
+
+//swap what's shown after a message, "sent", "#12 delivered", "#12 read by bob" ..
+static void set_message_state(GtkWidget *text_view, const char *id, const char *state) {
+    GtkTextBuffer *buffer = gtk_text_view_get_buffer(GTK_TEXT_VIEW(text_view));
+    char start_name[64], end_name[64];
+    snprintf(start_name, sizeof(start_name), "msg-%s-state-start", id);
+    snprintf(end_name, sizeof(end_name), "msg-%s-state-end", id);
+    GtkTextMark *start_mark = gtk_text_buffer_get_mark(buffer, start_name);
+    GtkTextMark *end_mark = gtk_text_buffer_get_mark(buffer, end_name);
+    if (!start_mark || !end_mark) return; //not on screen
+
+    GtkTextIter start, end;
+    gtk_text_buffer_get_iter_at_mark(buffer, &start, start_mark);
+    gtk_text_buffer_get_iter_at_mark(buffer, &end, end_mark);
+    gtk_text_buffer_delete(buffer, &start, &end);
+    gtk_text_buffer_insert_with_tags_by_name(buffer, &start, state, -1, "state", NULL);
+    gtk_text_buffer_move_mark(buffer, end_mark, &start);
+}
+
+//"sent <local>", only while nothing newer came in for it
+static void message_sent(GtkWidget *text_view, const char *local) {
+    GtkTextBuffer *buffer = gtk_text_view_get_buffer(GTK_TEXT_VIEW(text_view));
+    char key[48], start_name[64], end_name[64];
+    snprintf(key, sizeof(key), "local-%s", local);
+    snprintf(start_name, sizeof(start_name), "msg-%s-state-start", key);
+    snprintf(end_name, sizeof(end_name), "msg-%s-state-end", key);
+    GtkTextMark *start_mark = gtk_text_buffer_get_mark(buffer, start_name);
+    GtkTextMark *end_mark = gtk_text_buffer_get_mark(buffer, end_name);
+    if (!start_mark || !end_mark) return;
+
+    GtkTextIter start, end;
+    gtk_text_buffer_get_iter_at_mark(buffer, &start, start_mark);
+    gtk_text_buffer_get_iter_at_mark(buffer, &end, end_mark);
+    g_autofree char *state = gtk_text_buffer_get_text(buffer, &start, &end, FALSE);
+    if (g_strcmp0(state, "sending") == 0) set_message_state(text_view, key, "sent");
+}
+
+//"delivered <local> <id>", from now on our message answers to its server id
+static void message_delivered(GtkWidget *text_view, const char *local, const char *id) {
+    GtkTextBuffer *buffer = gtk_text_view_get_buffer(GTK_TEXT_VIEW(text_view));
+    const char *parts[] = {"start", "end", "state-start", "state-end"};
+    for (size_t i = 0; i < G_N_ELEMENTS(parts); i++) {
+        char mark_name[64];
+        snprintf(mark_name, sizeof(mark_name), "msg-local-%s-%s", local, parts[i]);
+        GtkTextMark *mark = gtk_text_buffer_get_mark(buffer, mark_name);
+        if (!mark) return;
+        GtkTextIter where;
+        gtk_text_buffer_get_iter_at_mark(buffer, &where, mark);
+        set_message_mark(buffer, id, parts[i], &where);
+    }
+    char state[64];
+    snprintf(state, sizeof(state), "#%s delivered", id);
+    set_message_state(text_view, id, state);
+}
+
+//"read <id> <user>", everyone who read it so far is kept on the buffer
+static void message_read(GtkWidget *text_view, const char *id, const char *reader) {
+    GtkTextBuffer *buffer = gtk_text_view_get_buffer(GTK_TEXT_VIEW(text_view));
+    char key[64];
+    snprintf(key, sizeof(key), "readers-%s", id);
+    const char *readers = g_object_get_data(G_OBJECT(buffer), key);
+    char *updated = readers ? g_strdup_printf("%s, %s", readers, reader) : g_strdup(reader);
+    g_object_set_data_full(G_OBJECT(buffer), key, updated, g_free);
+    g_autofree char *state = g_strdup_printf("#%s read by %s", id, updated);
+    set_message_state(text_view, id, state);
+}
+
+//click on a markdown link opens it in the browser
+static gboolean on_link_event(GtkTextTag *tag, GObject *view, GdkEvent *event, const GtkTextIter *iter, gpointer data) {
+    if (event->type != GDK_BUTTON_RELEASE || ((GdkEventButton *)event)->button != 1) return FALSE;
+    const char *url = g_object_get_data(G_OBJECT(tag), "url");
+    if (url) gtk_show_uri_on_window(GTK_WINDOW(gtk_widget_get_toplevel(GTK_WIDGET(view))), url, GDK_CURRENT_TIME, NULL);
+    return FALSE;
+}
+
+//markdown from rust_client, "spans=bold:0:4 link:5:9:<url> .." in characters from where the message text starts
+static void apply_spans(GtkWidget *text_view, const char *id, const char *spans) {
+    GtkTextBuffer *buffer = gtk_text_view_get_buffer(GTK_TEXT_VIEW(text_view));
+    char mark_name[64];
+    snprintf(mark_name, sizeof(mark_name), "msg-%s-start", id);
+    GtkTextMark *start_mark = gtk_text_buffer_get_mark(buffer, mark_name);
+    if (!start_mark || !spans[0]) return;
+
+    GtkTextTagTable *tag_table = gtk_text_buffer_get_tag_table(buffer);
+    if (!gtk_text_tag_table_lookup(tag_table, "md-bold")) {
+        gtk_text_buffer_create_tag(buffer, "md-bold", "weight", PANGO_WEIGHT_BOLD, NULL);
+        gtk_text_buffer_create_tag(buffer, "md-italic", "style", PANGO_STYLE_ITALIC, NULL);
+        gtk_text_buffer_create_tag(buffer, "md-code", "family", "monospace", "background", "#3a3a3a", NULL);
+        gtk_text_buffer_create_tag(buffer, "md-codeblock", "family", "monospace", "paragraph-background", "#2b2b2b", NULL);
+    }
+
+    char *copy = g_strdup(spans);
+    char *save = NULL;
+    for (char *kind = strtok_r(copy, " ", &save); kind; kind = strtok_r(NULL, " ", &save)) {
+        char *rest = strchr(kind, ':');
+        if (!rest) continue;
+        *rest++ = '\0';
+        long from = strtol(rest, &rest, 10);
+        if (*rest != ':') continue;
+        long to = strtol(rest + 1, &rest, 10);
+        if (from < 0 || to <= from) continue;
+
+        GtkTextIter start, end;
+        gtk_text_buffer_get_iter_at_mark(buffer, &start, start_mark);
+        end = start;
+        gtk_text_iter_forward_chars(&start, (gint)from);
+        gtk_text_iter_forward_chars(&end, (gint)to);
+        if (g_strcmp0(kind, "link") == 0 && *rest == ':') {
+            //one tag per link so it can carry its url
+            GtkTextTag *link = gtk_text_buffer_create_tag(buffer, NULL, "foreground", "#6cb6ff", "underline", PANGO_UNDERLINE_SINGLE, NULL);
+            g_object_set_data_full(G_OBJECT(link), "url", g_strdup(rest + 1), g_free);
+            g_signal_connect(link, "event", G_CALLBACK(on_link_event), NULL);
+            gtk_text_buffer_apply_tag(buffer, link, &start, &end);
+        } else {
+            char tag_name[32];
+            snprintf(tag_name, sizeof(tag_name), "md-%s", kind);
+            if (gtk_text_tag_table_lookup(tag_table, tag_name)) {
+                gtk_text_buffer_apply_tag_by_name(buffer, tag_name, &start, &end);
+            }
+        }
+    }
+    g_free(copy);
+}
+
+//pulls "key=value" out of an event's "@a=b;c=d" tags, undoing the \s \: \\ escapes
+static void get_event_tag(const char *tags, const char *key, char *out, size_t out_size) {
+    out[0] = '\0';
+    size_t key_len = strlen(key);
+    const char *p = tags;
+    while (p && *p) {
+        if (strncmp(p, key, key_len) == 0 && p[key_len] == '=') {
+            p += key_len + 1;
+            size_t len = 0;
+            while (*p && *p != ';' && *p != ' ' && len < out_size - 1) {
+                char c = *p++;
+                if (c == '\\' && *p) {
+                    c = *p++;
+                    if (c == 's') c = ' ';
+                    else if (c == ':') c = ';';
+                    else if (c == 'n') c = '\n';
+                    else if (c == 'r') c = '\r';
+                }
+                out[len++] = c;
+            }
+            out[len] = '\0';
+            return;
+        }
+        p = strchr(p, ';');
+        if (p) p++;
+    }
+}
+
+//true when the tags contain the flag "key" (a tag without a value)
+static gboolean has_event_tag(const char *tags, const char *key) {
+    size_t key_len = strlen(key);
+    const char *p = tags;
+    while (p && *p) {
+        if (strncmp(p, key, key_len) == 0 && (p[key_len] == ';' || p[key_len] == ' ' || p[key_len] == '\0'))
+            return TRUE;
+        p = strchr(p, ';');
+        if (p) p++;
+    }
+    return FALSE;
+}
+
+//someone @mentioned us: colour the message, and for live ones ask the window manager for attention
+void highlight_chat_message(GtkWidget *text_view, const char *id, gboolean alert) {
+    GtkTextBuffer *buffer = gtk_text_view_get_buffer(GTK_TEXT_VIEW(text_view));
+    GtkTextTagTable *tag_table = gtk_text_buffer_get_tag_table(buffer);
+    if (!gtk_text_tag_table_lookup(tag_table, "highlight")) {
+        gtk_text_buffer_create_tag(buffer, "highlight", "foreground", "black", "background", "yellow", NULL);
+    }
+    char start_name[64], end_name[64];
+    snprintf(start_name, sizeof(start_name), "msg-%s-start", id);
+    snprintf(end_name, sizeof(end_name), "msg-%s-end", id);
+    GtkTextMark *start_mark = gtk_text_buffer_get_mark(buffer, start_name);
+    GtkTextMark *end_mark = gtk_text_buffer_get_mark(buffer, end_name);
+    if (start_mark && end_mark) {
+        GtkTextIter start, end;
+        gtk_text_buffer_get_iter_at_mark(buffer, &start, start_mark);
+        gtk_text_buffer_get_iter_at_mark(buffer, &end, end_mark);
+        gtk_text_buffer_apply_tag_by_name(buffer, "highlight", &start, &end);
+    }
+    if (!alert) return;
+
+    GtkWidget *window = gtk_widget_get_toplevel(text_view);
+    if (GTK_IS_WINDOW(window) && !gtk_window_is_active(GTK_WINDOW(window))) {
+        gtk_window_set_urgency_hint(GTK_WINDOW(window), TRUE);
+    }
+    gtk_widget_error_bell(text_view);
+}
+
 //-------------------------------------------------------------------------------------------------------
 
+//the server is password protected, rust_client waits for the password on its stdin
+static void ask_password(GtkWidget *text_view, const char *prompt) {
+    GtkWidget *dialog = gtk_dialog_new_with_buttons("Password", GTK_WINDOW(gtk_widget_get_toplevel(text_view)), GTK_DIALOG_MODAL,
+                                                    "_Join", GTK_RESPONSE_ACCEPT, "_Cancel", GTK_RESPONSE_CANCEL, NULL);
+    GtkWidget *content = gtk_dialog_get_content_area(GTK_DIALOG(dialog));
+    gtk_container_add(GTK_CONTAINER(content), gtk_label_new(prompt));
+    GtkWidget *entry = gtk_entry_new();
+    gtk_entry_set_visibility(GTK_ENTRY(entry), FALSE);
+    gtk_entry_set_activates_default(GTK_ENTRY(entry), TRUE);
+    gtk_container_add(GTK_CONTAINER(content), entry);
+    gtk_dialog_set_default_response(GTK_DIALOG(dialog), GTK_RESPONSE_ACCEPT);
+    gtk_widget_show_all(dialog);
+    if (gtk_dialog_run(GTK_DIALOG(dialog)) == GTK_RESPONSE_ACCEPT) {
+        rust_bridge_send(gtk_entry_get_text(GTK_ENTRY(entry)));
+    } else {
+        rust_bridge_send(""); //no password, the server turns us away and rust_client says so
+    }
+    gtk_widget_destroy(dialog);
+}
+
 void handle_rust_incoming_message(const char *incoming, gpointer user_data) {
     if (!incoming) return;
-    GtkTextView *text_view = GTK_TEXT_VIEW(user_data);
-    char buffer[1024];
-    strncpy(buffer, incoming, sizeof(buffer));
-    buffer[sizeof(buffer) - 1] = '\0';
-    // Find the first colon (assuming format is "username: message")
-    char *colon = strchr(buffer, ':');
-    if (!colon) return;
-
-    *colon = '\0';
-    const char *name = buffer;
-    const char *msg = colon + 2; // skip ": " (colon and space)
-    add_chat_message(user_data,name, msg, TRUE);
+    g_autofree char *buffer = g_strdup(incoming); // the whole event, we cut it up in place
+    // one whole event per call, no newline: "[@tags] <event> <name> :<text>" or "<event> :<text>"
+    // the ':' is left out when the text is a single word
+    char *line = buffer;
+    char msg_id[32] = "";
+    char local[32] = "";
+    char to[128] = "";
+    char more[32] = "";
+    char room[128] = "";
+    char by[128] = "";
+    char quote_from[128] = "";
+    char quote[256] = "";
+    gboolean highlight = FALSE;
+    gboolean replay = FALSE;
+    gboolean motd = FALSE;
+    char spans[512] = "";
+    char ts[32] = "";
+    if (line[0] == '@') {
+        line = strchr(line, ' ');
+        if (!line) return;
+        *line++ = '\0';
+        get_event_tag(buffer + 1, "id", msg_id, sizeof(msg_id));
+        get_event_tag(buffer + 1, "local", local, sizeof(local));
+        get_event_tag(buffer + 1, "to", to, sizeof(to));
+        get_event_tag(buffer + 1, "more", more, sizeof(more));
+        get_event_tag(buffer + 1, "room", room, sizeof(room));
+        get_event_tag(buffer + 1, "by", by, sizeof(by));
+        get_event_tag(buffer + 1, "quote_from", quote_from, sizeof(quote_from));
+        get_event_tag(buffer + 1, "quote", quote, sizeof(quote));
+        highlight = has_event_tag(buffer + 1, "highlight");
+        replay = has_event_tag(buffer + 1, "replay");
+        motd = has_event_tag(buffer + 1, "motd");
+        get_event_tag(buffer + 1, "ts", ts, sizeof(ts));
+        get_event_tag(buffer + 1, "spans", spans, sizeof(spans));
+    }
+    // the server stamps what it relays, local time only for rust_client's own events
+    time_t when = ts[0] ? (time_t)strtoll(ts, NULL, 10) : time(NULL);
+    // our own messages come back from rust_client as "local=<n>" until the server acks them
+    char local_key[48] = "";
+    if (local[0]) snprintf(local_key, sizeof(local_key), "local-%s", local);
+    const char *id = msg_id[0] ? msg_id : (local[0] ? local_key : NULL);
+    gboolean ours = local[0] != '\0';
+    char *text = strstr(line, " :");
+    if (text) {
+        *text = '\0';
+        text += 2; // skip " :"
+    } else {
+        text = strrchr(line, ' ');
+        if (!text) return;
+        *text++ = '\0';
+    }
+
+    char *event = line;
+    char *name = strchr(line, ' ');
+    if (name) *name++ = '\0';
+
+    char label[160];
+    if (quote_from[0] && (g_strcmp0(event, "msg") == 0 || g_strcmp0(event, "action") == 0)) {
+        // a reply, rust_client tells us who and what it answers
+        snprintf(label, sizeof(label), "  \u21aa %s", quote_from);
+        add_chat_message(user_data, label, quote, TRUE, NULL, when);
+    }
+    if (g_strcmp0(event, "msg") == 0 && name) {
+        add_chat_message(user_data, name, text, !ours, id, when);
+        apply_spans(user_data, "last", spans);
+        if (highlight && id) highlight_chat_message(user_data, id, !replay);
+    } else if (g_strcmp0(event, "private") == 0 && name) {
+        if (ours) snprintf(label, sizeof(label), "%s \u2192 %s (private)", name, to);
+        else snprintf(label, sizeof(label), "%s (private)", name);
+        add_chat_message(user_data, label, text, !ours, id, when);
+        apply_spans(user_data, "last", spans);
+    } else if (g_strcmp0(event, "action") == 0 && name) {
+        snprintf(label, sizeof(label), "* %s", name);
+        add_chat_message(user_data, label, text, !ours, id, when);
+        apply_spans(user_data, "last", spans);
+        if (highlight && id) highlight_chat_message(user_data, id, !replay);
+    } else if (g_strcmp0(event, "sent") == 0) {
+        // "sent <local>", the local id ends up in text
+        message_sent(user_data, text);
+    } else if (g_strcmp0(event, "delivered") == 0 && name) {
+        // "delivered <local> <id>"
+        message_delivered(user_data, name, text);
+    } else if (g_strcmp0(event, "read") == 0 && name) {
+        // "read <id> <user>"
+        message_read(user_data, name, text);
+    } else if (g_strcmp0(event, "replace") == 0 && name) {
+        // "replace <id> :<text>"
+        replace_chat_message(user_data, name, text);
+        apply_spans(user_data, name, spans);
+    } else if (g_strcmp0(event, "retract") == 0) {
+        // "retract <id>", the id ends up in text since there's nothing after it
+        replace_chat_message(user_data, text, "(message deleted)");
+    } else if (g_strcmp0(event, "reaction") == 0 && name) {
+        // "reaction <id> <emoji> <count>", name holds "<id> <emoji>" and text the count
+        char *emoji = strchr(name, ' ');
+        if (!emoji) return;
+        *emoji++ = '\0';
+        snprintf(label, sizeof(label), "%s reacted %s on #%s, now", by[0] ? by : "someone", emoji, name);
+        add_chat_message(user_data, label, text, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "thread") == 0 && name) {
+        // "thread <root> <count>", the thread's messages follow as normal msg events
+        snprintf(label, sizeof(label), "thread #%s, messages:", name);
+        add_chat_message(user_data, label, text, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "results") == 0 && name) {
+        // "[@more=<id>] results <count> :<words>", the hits follow
+        g_autofree char *summary = g_strdup_printf("%s found for \"%s\"%s", name, text, more[0] ? ", /more for older ones" : "");
+        add_chat_message(user_data, "search", summary, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "hit") == 0 && name) {
+        // "@id=..;ts=..;room=.. hit <from> :<excerpt>", shown at the time it was said.
+        // no marks for it, the id belongs to the message itself if it's on screen
+        snprintf(label, sizeof(label), "  #%s %s in %s", msg_id, name, room);
+        add_chat_message(user_data, label, text, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "room") == 0) {
+        add_chat_message(user_data, "now in room", text, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "roster") == 0) {
+        // "roster <user> <user>..", the last name ends up in text
+        g_autofree char *online = name ? g_strdup_printf("%s %s", name, text) : g_strdup(text);
+        g_auto(GStrv) names = g_strsplit(online, " ", -1);
+        g_autofree char *listed = g_strjoinv(", ", names);
+        add_chat_message(user_data, "online", listed, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "presence") == 0 && name) {
+        // "presence <user> online|offline", a roster sidebar can hook in here too
+        snprintf(label, sizeof(label), "%s is", name);
+        add_chat_message(user_data, label, text, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "nick") == 0 && name) {
+        // "nick <old> <new>", our own messages are echoed under finalname so keep it in step
+        if (g_strcmp0(name, finalname) == 0) {
+            g_strlcpy(finalname, text, sizeof(finalname));
+        }
+        snprintf(label, sizeof(label), "%s is now known as", name);
+        add_chat_message(user_data, label, text, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "typing") == 0 && name) {
+        // "typing <user> start|stop", shown under the chat instead of in it
+        if (typing_label) {
+            if (g_str_has_prefix(text, "start")) {
+                snprintf(label, sizeof(label), "%s is typing...", name);
+                gtk_label_set_text(GTK_LABEL(typing_label), label);
+            } else {
+                gtk_label_set_text(GTK_LABEL(typing_label), "");
+            }
+        }
+    } else if (g_strcmp0(event, "offer") == 0 && name && id) {
+        // "offer <from> <size> <sha256> :<file name>", the id tag is the transfer
+        char from[128];
+        unsigned long long size = 0;
+        if (sscanf(name, "%127s %llu", from, &size) != 2) return;
+        char offer[512];
+        snprintf(offer, sizeof(offer), "%s (%llu bytes), type /accept %s or /reject %s", text, size, id, id);
+        snprintf(label, sizeof(label), "%s offers a file", from);
+        add_chat_message(user_data, label, offer, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "offered") == 0 && name) {
+        // "offered <transfer> :<file name>"
+        snprintf(label, sizeof(label), "file #%s offered", name);
+        add_chat_message(user_data, label, text, TRUE, NULL, when);
+    } else if ((g_strcmp0(event, "accepted") == 0 || g_strcmp0(event, "rejected") == 0 ||
+                g_strcmp0(event, "cancelled") == 0 || g_strcmp0(event, "uploaded") == 0) && name) {
+        // "<event> <transfer> <user> [offset]", the last word ends up in text
+        snprintf(label, sizeof(label), "file #%s", name);
+        char status[256];
+        snprintf(status, sizeof(status), "%s (%s)", event, text);
+        add_chat_message(user_data, label, status, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "cancelled") == 0) {
+        // "cancelled <transfer>", the sender left
+        snprintf(label, sizeof(label), "file #%s", text);
+        add_chat_message(user_data, label, "cancelled, the sender left", TRUE, NULL, when);
+    } else if (g_strcmp0(event, "progress") == 0 && name) {
+        // "progress <transfer> <done> <size>", name holds "<transfer> <done>"
+        char transfer[32];
+        unsigned long long done = 0, size = strtoull(text, NULL, 10);
+        if (sscanf(name, "%31s %llu", transfer, &done) != 2 || !transfer_label) return;
+        snprintf(label, sizeof(label), "file #%s: %llu%%", transfer, size ? done * 100 / size : 100);
+        gtk_label_set_text(GTK_LABEL(transfer_label), label);
+    } else if (g_strcmp0(event, "received") == 0 && name) {
+        snprintf(label, sizeof(label), "file #%s saved to", name);
+        add_chat_message(user_data, label, text, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "failed") == 0 && name) {
+        snprintf(label, sizeof(label), "file #%s failed", name);
+        add_chat_message(user_data, label, text, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "system") == 0) {
+        add_chat_message(user_data, motd ? "motd" : "system", text, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "error") == 0) {
+        add_chat_message(user_data, "error", text, TRUE, NULL, when);
+    } else if (g_strcmp0(event, "password") == 0) {
+        ask_password(user_data, text);
+    }
     // g_free(name);
     // g_free(msg);
 }
 
 //chat window, when connect button clicked---------------------------------------------------------------
 
+//tell rust_client when we start/stop typing, it only goes out on a change
+void on_entry_changed(GtkEditable *editable, gpointer data) {
+    gboolean has_text = g_strcmp0(gtk_entry_get_text(GTK_ENTRY(editable)), "") != 0;
+    if (has_text != is_typing) {
+        is_typing = has_text;
+        rust_bridge_send(is_typing ? "/typing start" : "/typing stop");
+    }
+}
+
 //send message
 void on_send_clicked(GtkWidget *widget, gpointer data) {
     ChatWidgets *widgets = (ChatWidgets *)data;
 
     const gchar *msg = gtk_entry_get_text(GTK_ENTRY(widgets->entry));
     if (g_strcmp0(msg, "") != 0) {
-        add_chat_message(widgets->chat_display, finalname, msg,FALSE);
+        //what we say comes back from rust_client as an event, with its id and state
         rust_bridge_send(msg);
         gtk_entry_set_text(GTK_ENTRY(widgets->entry), "");  // clear entry
     }
@@ -310,6 +753,18 @@ void on_connect_clicked(GtkWidget *widget, gpointer data) {
      gtk_widget_set_can_focus(chat_display, FALSE);
      gtk_widget_set_name(chat_display, "chat_display");
  
+     // typing indicator
+     typing_label = gtk_label_new("");
+     gtk_widget_set_halign(typing_label, GTK_ALIGN_START);
+     gtk_widget_set_name(typing_label, "typinglabel"); //for css
+     gtk_box_pack_start(GTK_BOX(chatvbox), typing_label, FALSE, FALSE, 0);
+
+     // file transfer progress
+     transfer_label = gtk_label_new("");
+     gtk_widget_set_halign(transfer_label, GTK_ALIGN_START);
+     gtk_widget_set_name(transfer_label, "transferlabel"); //for css
+     gtk_box_pack_start(GTK_BOX(chatvbox), transfer_label, FALSE, FALSE, 0);
+
      // Message input area
      GtkWidget *bottom_box = gtk_box_new(GTK_ORIENTATION_HORIZONTAL, 5);
      gtk_box_pack_end(GTK_BOX(chatvbox), bottom_box, FALSE, FALSE, 10);
@@ -332,6 +787,7 @@ void on_connect_clicked(GtkWidget *widget, gpointer data) {
      gtk_button_set_relief(GTK_BUTTON(send_btn), GTK_RELIEF_NONE); //for css
      g_signal_connect(send_btn, "clicked", G_CALLBACK(on_send_clicked), chat_widgets);
      g_signal_connect(messageentry, "activate", G_CALLBACK(on_send_clicked), chat_widgets);
+     g_signal_connect(messageentry, "changed", G_CALLBACK(on_entry_changed), NULL);
 
      rust_bridge_start(handle_rust_incoming_message, chat_display, finalname);
  
@@ -1,12 +1,37 @@
 use std::{
     collections::HashMap,
-    io::{Read, Write, BufReader, BufRead},
-    net::{TcpListener, TcpStream, UdpSocket},
+    net::{IpAddr, UdpSocket},
     sync::{Arc, Mutex},
-    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use nameless_proto::lobby::{INVITE_REGISTERED, InviteEntry, LOBBY_PORT, Listing, Request, ServerEntry};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+type ServerList = Arc<Mutex<HashMap<String, ServerInfo>>>; // ip -> server
+
+type InviteList = Arc<Mutex<HashMap<String, Invite>>>; // code -> invite
 
-type ServerList = Arc<Mutex<HashMap<String, String>>>; // ip -> name
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10); // to send the one request line
+const REQUEST_MAX: u64 = 1024;
+const INVITES_PER_SERVER: usize = 100; // live ones, so one server can't fill the lobby's memory
+
+struct ServerInfo {
+    name: String,
+    locked: bool, // needs a password to join
+    peer: IpAddr, // where it registered from, only that host gets to mint its invites
+}
+
+// Minted by a server, resolved here so the client lands on that server and room
+struct Invite {
+    ip: String,
+    room: String,
+    until: Option<u64>,     // unix seconds, None never expires
+    uses_left: Option<u32>, // None for unlimited
+}
 
 fn get_ip() -> String {
     let socket = UdpSocket::bind("0.0.0.0:0").expect("Failed to bind socket for IP detection");
@@ -14,59 +39,177 @@ fn get_ip() -> String {
     socket.local_addr().unwrap().ip().to_string()
 }
 
-fn main() {
-    let listener = TcpListener::bind("0.0.0.0:8080").expect("Could not bind listener");
+#[tokio::main]
+async fn main() {
+    let listener = TcpListener::bind(("0.0.0.0", LOBBY_PORT)).await.expect("Could not bind listener");
     let servers: ServerList = Arc::new(Mutex::new(HashMap::new()));
+    let invites: InviteList = Arc::new(Mutex::new(HashMap::new()));
 
     let ip_addr = get_ip();
-    println!("IP address of this lobby: {}:8080", ip_addr);
+    println!("IP address of this lobby: {}:{}", ip_addr, LOBBY_PORT);
 
-    for stream in listener.incoming() {
-        let servers = Arc::clone(&servers);
-        let mut stream = stream.expect("Failed to accept connection");
-
-        thread::spawn(move || {
-            let mut reader = BufReader::new(&stream);
-            let mut request = String::new();
-            if reader.read_line(&mut request).is_err() {
-                eprintln!("Failed to read from client");
-                return;
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
             }
+        };
+        let servers = Arc::clone(&servers);
+        let invites = Arc::clone(&invites);
+        tokio::spawn(handle_request(stream, servers, invites));
+    }
+}
 
-            if request.starts_with("server") {
-                let (ip, name) = get_server_info(&request);
-                add_server(servers, ip, name);
-            } else if request.starts_with("client") {
-                if let Err(e) = send_server_address(&mut stream, &servers) {
-                    eprintln!("Failed to send server address: {}", e);
-                }
+// Every connection carries one request line, and clients get one line back
+async fn handle_request(mut stream: TcpStream, servers: ServerList, invites: InviteList) {
+    let Ok(peer) = stream.peer_addr().map(|addr| addr.ip()) else {
+        return;
+    };
+    let mut request = String::new();
+    let mut reader = BufReader::new((&mut stream).take(REQUEST_MAX));
+    match time::timeout(REQUEST_TIMEOUT, reader.read_line(&mut request)).await {
+        Ok(Ok(_)) => {}
+        _ => {
+            eprintln!("Failed to read from client");
+            return;
+        }
+    }
+
+    match Request::parse(&request) {
+        Some(Request::Server(ServerEntry { ip, locked, name })) => add_server(servers, ip, ServerInfo { name, locked, peer }),
+        Some(Request::Invite(InviteEntry { code, ip, room, until, uses })) => {
+            let reply = add_invite(&servers, &invites, code, Invite { ip, room, until, uses_left: uses }, peer);
+            if let Err(e) = stream.write_all(reply.as_bytes()).await {
+                eprintln!("Failed to answer invite: {}", e);
+            }
+        }
+        Some(Request::Client { code, .. }) => {
+            let reply = match code {
+                Some(code) => invite_address(&servers, &invites, &code),
+                None => server_address(&servers),
+            };
+            if let Err(e) = stream.write_all(reply.as_bytes()).await {
+                eprintln!("Failed to send server address: {}", e);
             }
-        });
+        }
+        None => eprintln!("Ignoring malformed request: {}", request.trim()),
     }
 }
 
-fn get_server_info(request: &str) -> (String, String) {
-    // Format: "server <ip> <name>"
-    let parts: Vec<&str> = request.trim().split_whitespace().collect();
-    if parts.len() < 3 {
-        return ("unknown".to_string(), "unnamed".to_string());
+// An address stays with the host that first registered it, otherwise anyone could take
+// over a listing and then mint invites to it
+fn add_server(servers: ServerList, ip: String, info: ServerInfo) {
+    let mut servers_lock = servers.lock().unwrap();
+    if let Some(existing) = servers_lock.get(&ip)
+        && existing.peer != info.peer
+    {
+        eprintln!("Refusing server '{}' at {} from {}, registered from {}", info.name, ip, info.peer, existing.peer);
+        return;
     }
-    (parts[1].to_string(), parts[2..].join(" "))
+    let lock = if info.locked { " (password protected)" } else { "" };
+    println!("Registered server '{}' at {}{}", info.name, ip, lock);
+    servers_lock.insert(ip, info);
 }
 
-fn add_server(servers: ServerList, ip: String, name: String) {
-    let mut servers_lock = servers.lock().unwrap();
-    servers_lock.insert(ip.clone(), name.clone());
-    println!("Registered server '{}' at {}", name, ip);
+// Only the host a server registered from can mint invites to it, and a code is never
+// handed out twice while the first one is still around. Each server gets INVITES_PER_SERVER.
+fn add_invite(servers: &ServerList, invites: &InviteList, code: String, invite: Invite, peer: IpAddr) -> String {
+    if servers.lock().unwrap().get(&invite.ip).is_none_or(|info| info.peer != peer) {
+        eprintln!("Refusing invite {} for {} from {}", code, invite.ip, peer);
+        return "Only that server can invite to it\n".to_string();
+    }
+    let mut invites_lock = invites.lock().unwrap();
+    let now = now();
+    invites_lock.retain(|_, invite| invite.until.is_none_or(|until| until > now) && invite.uses_left != Some(0));
+    if invites_lock.contains_key(&code) {
+        return "Invite code is already taken\n".to_string();
+    }
+    if invites_lock.values().filter(|other| other.ip == invite.ip).count() >= INVITES_PER_SERVER {
+        return "Too many open invites for this server\n".to_string();
+    }
+    println!("Registered invite {} for room '{}' on {}", code, invite.room, invite.ip);
+    invites_lock.insert(code, invite);
+    format!("{}\n", INVITE_REGISTERED)
+}
+
+// Like server_address plus the room to join, every resolution uses the invite up once
+fn invite_address(servers: &ServerList, invites: &InviteList, code: &str) -> String {
+    let mut invites_lock = invites.lock().unwrap();
+    match invites_lock.get_mut(&code.to_uppercase()) {
+        None => "Unknown invite code\n".to_string(),
+        Some(invite) if invite.until.is_some_and(|until| until <= now()) => "Invite code has expired\n".to_string(),
+        Some(invite) if invite.uses_left == Some(0) => "Invite code has been used up\n".to_string(),
+        Some(invite) => match servers.lock().unwrap().get(&invite.ip) {
+            Some(info) => {
+                invite.uses_left = invite.uses_left.map(|uses| uses - 1);
+                format!("{}\n", Listing::new(&invite.ip, info.locked, Some(&invite.room)))
+            }
+            None => "The server for this invite is gone\n".to_string(),
+        },
+    }
 }
 
-fn send_server_address(stream: &mut TcpStream, servers: &ServerList) -> std::io::Result<()> {
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn server_address(servers: &ServerList) -> String {
     let servers_lock = servers.lock().unwrap();
-    if let Some((ip, _name)) = servers_lock.iter().next() {
-        let full_address = format!("{}:8081\n", ip);
-        stream.write_all(full_address.as_bytes())?;
-    } else {
-        stream.write_all(b"No servers available\n")?;
+    // A locked server asks for its password
+    match servers_lock.iter().next() {
+        Some((ip, info)) => format!("{}\n", Listing::new(ip, info.locked, None)),
+        None => "No servers available\n".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invite(ip: &str) -> Invite {
+        Invite { ip: ip.to_string(), room: "ops".to_string(), until: None, uses_left: Some(1) }
+    }
+
+    #[test]
+    fn invites_come_only_from_their_server_and_codes_stay_unique() {
+        let servers: ServerList = Arc::new(Mutex::new(HashMap::new()));
+        let invites: InviteList = Arc::new(Mutex::new(HashMap::new()));
+        let (host, other): (IpAddr, IpAddr) = ("10.0.0.2".parse().unwrap(), "10.0.0.9".parse().unwrap());
+        add_server(Arc::clone(&servers), "10.0.0.2".to_string(), ServerInfo { name: "home".to_string(), locked: false, peer: host });
+
+        let registered = format!("{}\n", INVITE_REGISTERED);
+        assert_ne!(add_invite(&servers, &invites, "AAAA-AAAA".to_string(), invite("10.0.0.2"), other), registered);
+        assert_ne!(add_invite(&servers, &invites, "AAAA-AAAA".to_string(), invite("10.0.0.5"), host), registered);
+        assert_eq!(add_invite(&servers, &invites, "AAAA-AAAA".to_string(), invite("10.0.0.2"), host), registered);
+        assert_ne!(add_invite(&servers, &invites, "AAAA-AAAA".to_string(), invite("10.0.0.2"), host), registered);
+
+        assert_eq!(invite_address(&servers, &invites, "aaaa-aaaa"), "10.0.0.2:8081 open ops\n");
+        assert_eq!(invite_address(&servers, &invites, "AAAA-AAAA"), "Invite code has been used up\n");
+
+        // Another host can't take the listing over, the server itself can come back
+        add_server(Arc::clone(&servers), "10.0.0.2".to_string(), ServerInfo { name: "stolen".to_string(), locked: true, peer: other });
+        assert_eq!(servers.lock().unwrap()["10.0.0.2"].name, "home");
+        assert_ne!(add_invite(&servers, &invites, "BBBB-BBBB".to_string(), invite("10.0.0.2"), other), registered);
+        add_server(Arc::clone(&servers), "10.0.0.2".to_string(), ServerInfo { name: "home again".to_string(), locked: false, peer: host });
+        assert_eq!(servers.lock().unwrap()["10.0.0.2"].name, "home again");
+    }
+
+    #[test]
+    fn servers_have_a_limit_on_open_invites() {
+        let servers: ServerList = Arc::new(Mutex::new(HashMap::new()));
+        let invites: InviteList = Arc::new(Mutex::new(HashMap::new()));
+        let host: IpAddr = "10.0.0.2".parse().unwrap();
+        add_server(Arc::clone(&servers), "10.0.0.2".to_string(), ServerInfo { name: "home".to_string(), locked: false, peer: host });
+        let registered = format!("{}\n", INVITE_REGISTERED);
+        for n in 0..INVITES_PER_SERVER {
+            assert_eq!(add_invite(&servers, &invites, format!("CODE-{}", n), invite("10.0.0.2"), host), registered);
+        }
+        assert_ne!(add_invite(&servers, &invites, "ONE-MORE".to_string(), invite("10.0.0.2"), host), registered);
+        // A used up invite makes room again
+        invite_address(&servers, &invites, "CODE-0");
+        assert_eq!(add_invite(&servers, &invites, "ONE-MORE".to_string(), invite("10.0.0.2"), host), registered);
     }
-    Ok(())
 }
@@ -0,0 +1,225 @@
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+};
+use aes_gcm::{Aes256Gcm, KeyInit};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub type AESKey = [u8; 32];
+pub type Identity = SigningKey; // a client's own key, kept across runs
+pub type UserKey = [u8; 32];    // its public half, what a server knows the client by
+type HmacSha256 = Hmac<Sha256>;
+
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+// The handshake is one text line at a time, frames only start once it's done:
+//   client "client <name> [last seen id]"
+//   open:   server "key <hex key>"
+//   locked: server "auth <hex salt> <hex challenge>"
+//           client "proof <hex hmac(key, "client" + challenge)>"
+//           server "ok <hex hmac(key, "server" + challenge)>" or "denied"
+//   server "who <hex challenge>"
+//   client "me <hex public key> <hex signature>"
+// On a locked server neither the password nor the key ever crosses the wire, the client
+// derives the key from the password and the salt. The last two lines tell the server who
+// the client is: it signs the room key and a fresh challenge, so the signature can't be
+// replayed elsewhere, and the server gives each name to the first key that used it.
+
+pub fn cipher(key: &AESKey) -> Aes256Gcm {
+    Aes256Gcm::new(key.into())
+}
+
+pub fn derive_key(password: &str, salt: &[u8]) -> AESKey {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    key
+}
+
+// "client <name> [last seen id]", the id asks for everything newer instead of the usual backlog
+#[derive(Debug, Clone, PartialEq)]
+pub struct Intro {
+    pub name: String,
+    pub since: Option<u64>,
+}
+
+impl Intro {
+    pub fn parse(line: &str) -> Option<Intro> {
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["client", name] => Some(Intro { name: name.to_string(), since: None }),
+            ["client", name, since] => Some(Intro { name: name.to_string(), since: since.parse().ok() }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Intro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client {}", self.name)?;
+        if let Some(since) = self.since {
+            write!(f, " {}", since)?;
+        }
+        Ok(())
+    }
+}
+
+// The server's first line
+#[derive(Debug, Clone, PartialEq)]
+pub enum Greeting {
+    Key(AESKey),
+    Auth { salt: Vec<u8>, challenge: Vec<u8> },
+}
+
+impl Greeting {
+    pub fn parse(line: &str) -> io::Result<Greeting> {
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["key", key] => decode(key)?.try_into().map(Greeting::Key).map_err(|_| invalid("room key has the wrong length")),
+            ["auth", salt, challenge] => Ok(Greeting::Auth { salt: decode(salt)?, challenge: decode(challenge)? }),
+            _ => Err(invalid(&format!("unexpected greeting from server: {}", line.trim_end()))),
+        }
+    }
+}
+
+impl fmt::Display for Greeting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Greeting::Key(key) => write!(f, "key {}", hex::encode(key)),
+            Greeting::Auth { salt, challenge } => write!(f, "auth {} {}", hex::encode(salt), hex::encode(challenge)),
+        }
+    }
+}
+
+pub const DENIED: &str = "denied";
+
+fn proof_mac(key: &AESKey, side: &str, challenge: &[u8]) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(side.as_bytes());
+    mac.update(challenge);
+    mac
+}
+
+// "proof <hex>", the client showing it knows the key
+pub fn proof(key: &AESKey, challenge: &[u8]) -> String {
+    format!("proof {}", hex::encode(proof_mac(key, "client", challenge).finalize().into_bytes()))
+}
+
+// verify_slice compares in constant time
+pub fn check_proof(key: &AESKey, challenge: &[u8], line: &str) -> bool {
+    let proof = match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["proof", proof] => hex::decode(proof).unwrap_or_default(),
+        _ => Vec::new(),
+    };
+    proof_mac(key, "client", challenge).verify_slice(&proof).is_ok()
+}
+
+// "ok <hex>", the server showing it knows the key too
+pub fn answer(key: &AESKey, challenge: &[u8]) -> String {
+    format!("ok {}", hex::encode(proof_mac(key, "server", challenge).finalize().into_bytes()))
+}
+
+pub fn check_answer(key: &AESKey, challenge: &[u8], line: &str) -> io::Result<()> {
+    match line.split_whitespace().collect::<Vec<_>>()[..] {
+        ["ok", answer] if proof_mac(key, "server", challenge).verify_slice(&decode(answer)?).is_ok() => Ok(()),
+        ["ok", _] => Err(io::Error::new(ErrorKind::PermissionDenied, "the server doesn't know the password")),
+        [DENIED] => Err(io::Error::new(ErrorKind::PermissionDenied, "wrong password")),
+        _ => Err(invalid(&format!("unexpected reply from server: {}", line.trim_end()))),
+    }
+}
+
+pub fn new_identity() -> Identity {
+    SigningKey::generate(&mut rand::rngs::OsRng)
+}
+
+// "who <hex>"
+pub fn who(challenge: &[u8]) -> String {
+    format!("who {}", hex::encode(challenge))
+}
+
+fn identity_message(key: &AESKey, challenge: &[u8]) -> Vec<u8> {
+    [b"nameless identity".as_slice(), key, challenge].concat()
+}
+
+// The client's answer to "who", "me <hex public key> <hex signature>"
+pub fn identify(identity: &Identity, key: &AESKey, who: &str) -> io::Result<String> {
+    let challenge = match who.split_whitespace().collect::<Vec<_>>()[..] {
+        ["who", challenge] => decode(challenge)?,
+        _ => return Err(invalid(&format!("unexpected line from server: {}", who.trim_end()))),
+    };
+    let signature = identity.sign(&identity_message(key, &challenge));
+    Ok(format!("me {} {}", hex::encode(identity.verifying_key().as_bytes()), hex::encode(signature.to_bytes())))
+}
+
+// The key the client proved it holds, None for anything else
+pub fn check_identity(key: &AESKey, challenge: &[u8], line: &str) -> Option<UserKey> {
+    let ["me", user_key, signature] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let user_key: UserKey = hex::decode(user_key).ok()?.try_into().ok()?;
+    let signature: [u8; 64] = hex::decode(signature).ok()?.try_into().ok()?;
+    let verifying = VerifyingKey::from_bytes(&user_key).ok()?;
+    verifying.verify_strict(&identity_message(key, challenge), &Signature::from_bytes(&signature)).ok()?;
+    Some(user_key)
+}
+
+fn decode(text: &str) -> io::Result<Vec<u8>> {
+    hex::decode(text).map_err(|_| invalid("bad hex in handshake"))
+}
+
+fn invalid(text: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, text.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intro_takes_an_optional_last_id() {
+        assert_eq!(Intro::parse("client bob\n"), Some(Intro { name: "bob".to_string(), since: None }));
+        let since = Intro { name: "bob".to_string(), since: Some(41) };
+        assert_eq!(Intro::parse(&since.to_string()), Some(since));
+        assert_eq!(Intro::parse("server bob"), None);
+        assert_eq!(Intro::parse("client"), None);
+    }
+
+    #[test]
+    fn greetings_round_trip() {
+        for greeting in [Greeting::Key([3u8; 32]), Greeting::Auth { salt: vec![1; 16], challenge: vec![2; 32] }] {
+            assert_eq!(Greeting::parse(&greeting.to_string()).unwrap(), greeting);
+        }
+        assert!(Greeting::parse("key abcd").is_err());
+        assert!(Greeting::parse("hello").is_err());
+    }
+
+    #[test]
+    fn both_sides_prove_the_same_key() {
+        let salt = [9u8; 16];
+        let key = derive_key("hunter2", &salt);
+        assert_eq!(key, derive_key("hunter2", &salt));
+        let challenge = [5u8; 32];
+        assert!(check_proof(&key, &challenge, &proof(&key, &challenge)));
+        assert!(!check_proof(&derive_key("hunter3", &salt), &challenge, &proof(&key, &challenge)));
+        assert!(!check_proof(&key, &challenge, "proof zz"));
+        assert!(check_answer(&key, &challenge, &answer(&key, &challenge)).is_ok());
+        assert_eq!(check_answer(&key, &challenge, DENIED).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        // Echoing our own proof back isn't an answer
+        assert!(check_answer(&key, &challenge, &proof(&key, &challenge).replace("proof", "ok")).is_err());
+    }
+
+    #[test]
+    fn identities_sign_for_one_room_and_challenge() {
+        let identity = new_identity();
+        let (key, challenge) = ([1u8; 32], [2u8; 32]);
+        let me = identify(&identity, &key, &who(&challenge)).unwrap();
+        assert_eq!(check_identity(&key, &challenge, &me), Some(*identity.verifying_key().as_bytes()));
+        // Not for another challenge or room, and not for someone else's key
+        assert_eq!(check_identity(&key, &[3u8; 32], &me), None);
+        assert_eq!(check_identity(&[4u8; 32], &challenge, &me), None);
+        let other = hex::encode(new_identity().verifying_key().as_bytes());
+        let words: Vec<&str> = me.split(' ').collect();
+        assert_eq!(check_identity(&key, &challenge, &format!("me {} {}", other, words[2])), None);
+        assert_eq!(check_identity(&key, &challenge, "me zz zz"), None);
+        assert!(identify(&identity, &key, "ok 1234").is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+use aes_gcm::{Aes256Gcm, Nonce};
+use aes_gcm::aead::Aead;
+use rand::RngCore;
+
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = NONCE_LEN + 2;
+
+// Wire format of every frame after the handshake: nonce (12) + size (2, BE) + ciphertext
+pub fn read_frame<R: Read>(reader: &mut R, cipher: &Aes256Gcm) -> io::Result<String> {
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    let mut ciphertext = vec![0u8; ciphertext_len(&header)];
+    reader.read_exact(&mut ciphertext)?;
+    open_frame(cipher, &header, &ciphertext)
+}
+
+// The same for the server's async connections
+#[cfg(feature = "tokio")]
+pub async fn read_frame_async<R: tokio::io::AsyncRead + Unpin>(reader: &mut R, cipher: &Aes256Gcm) -> io::Result<String> {
+    use tokio::io::AsyncReadExt;
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header).await?;
+    let mut ciphertext = vec![0u8; ciphertext_len(&header)];
+    reader.read_exact(&mut ciphertext).await?;
+    open_frame(cipher, &header, &ciphertext)
+}
+
+fn ciphertext_len(header: &[u8; HEADER_LEN]) -> usize {
+    u16::from_be_bytes([header[NONCE_LEN], header[NONCE_LEN + 1]]) as usize
+}
+
+fn open_frame(cipher: &Aes256Gcm, header: &[u8; HEADER_LEN], ciphertext: &[u8]) -> io::Result<String> {
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&header[..NONCE_LEN]), ciphertext)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "failed to decrypt frame"))?;
+    String::from_utf8(plaintext).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "frame is not valid UTF-8"))
+}
+
+// Encrypts a line into a ready-to-send packet, so one packet can be written to many clients
+pub fn seal_frame(cipher: &Aes256Gcm, plaintext: &str) -> io::Result<Vec<u8>> {
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce_bytes);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce_bytes), plaintext.as_bytes())
+        .map_err(|_| io::Error::other("encryption failed"))?;
+    let size = u16::try_from(ciphertext.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+
+    let mut packet = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+    packet.extend_from_slice(&nonce_bytes);
+    packet.extend_from_slice(&size.to_be_bytes());
+    packet.extend_from_slice(&ciphertext);
+    Ok(packet)
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, cipher: &Aes256Gcm, line: &Line) -> io::Result<()> {
+    writer.write_all(&seal_frame(cipher, &line.to_string())?)
+}
+
+// Plaintext of a frame: "[@key=value;key] <cmd> <arg> <arg> :<trailing text>"
+// Only the last argument may contain spaces, it gets the ':' prefix.
+// Tags carry metadata such as server message ids, values are escaped IRCv3 style
+// (' ' -> \s, ';' -> \:, '\' -> \\) so they can hold any text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub tags: Vec<(String, String)>,
+    pub cmd: String,
+    pub args: Vec<String>,
+}
+
+impl Line {
+    pub fn new(cmd: &str) -> Self {
+        Line { tags: Vec::new(), cmd: cmd.to_string(), args: Vec::new() }
+    }
+
+    pub fn tag(mut self, key: &str, value: impl ToString) -> Self {
+        self.tags.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn get_tag(&self, key: &str) -> Option<&str> {
+        self.tags.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn parse(raw: &str) -> Option<Line> {
+        let raw = raw.trim_end_matches(['\r', '\n']);
+        let (tags, raw) = match raw.strip_prefix('@') {
+            Some(tagged) => {
+                let (tags, rest) = tagged.split_once(' ')?;
+                (parse_tags(tags), rest.trim_start())
+            }
+            None => (Vec::new(), raw),
+        };
+        let (head, trailing) = match raw.find(" :") {
+            Some(pos) => (&raw[..pos], Some(&raw[pos + 2..])),
+            None => (raw, None),
+        };
+        let mut words = head.split_whitespace();
+        let cmd = words.next()?.to_string();
+        let mut args: Vec<String> = words.map(str::to_string).collect();
+        if let Some(text) = trailing {
+            args.push(text.to_string());
+        }
+        Some(Line { tags, cmd, args })
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.args.get(index).map(String::as_str)
+    }
+}
+
+fn parse_tags(raw: &str) -> Vec<(String, String)> {
+    raw.split(';')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| match tag.split_once('=') {
+            Some((k, v)) => (k.to_string(), unescape_tag_value(v)),
+            None => (tag.to_string(), String::new()),
+        })
+        .collect()
+}
+
+fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.tags.is_empty() {
+            let tags: Vec<String> = self
+                .tags
+                .iter()
+                .map(|(k, v)| if v.is_empty() { k.clone() } else { format!("{}={}", k, escape_tag_value(v)) })
+                .collect();
+            write!(f, "@{} ", tags.join(";"))?;
+        }
+        write!(f, "{}", self.cmd)?;
+        if let Some((last, middle)) = self.args.split_last() {
+            for arg in middle {
+                write!(f, " {}", arg)?;
+            }
+            if last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                write!(f, " :{}", last)?;
+            } else {
+                write!(f, " {}", last)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::KeyInit;
+
+    #[test]
+    fn lines_round_trip_with_escaped_tags() {
+        let line = Line::new("msg").tag("id", 7).tag("replay", "").tag("note", "a b;c\\d").arg("bob").arg("hi there");
+        assert_eq!(line.to_string(), "@id=7;replay;note=a\\sb\\:c\\\\d msg bob :hi there");
+        assert_eq!(Line::parse(&line.to_string()), Some(line));
+    }
+
+    #[test]
+    fn only_the_last_argument_is_trailing() {
+        let line = Line::parse("dm bob ::) ok\r\n").unwrap();
+        assert_eq!(line.args, vec!["bob", ":) ok"]);
+        assert_eq!(Line::new("x").arg("").to_string(), "x :");
+        assert_eq!(Line::parse("@id=1"), None);
+        assert_eq!(Line::parse(""), None);
+    }
+
+    #[test]
+    fn frames_open_only_with_their_key() {
+        let cipher = Aes256Gcm::new(&[7u8; 32].into());
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &cipher, &Line::new("say").arg("hello")).unwrap();
+        write_frame(&mut wire, &cipher, &Line::new("who")).unwrap();
+        let mut reader = wire.as_slice();
+        assert_eq!(read_frame(&mut reader, &cipher).unwrap(), "say hello");
+        assert_eq!(read_frame(&mut reader, &cipher).unwrap(), "who");
+
+        let other = Aes256Gcm::new(&[8u8; 32].into());
+        let packet = seal_frame(&cipher, "say hello").unwrap();
+        assert_eq!(read_frame(&mut packet.as_slice(), &other).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(seal_frame(&cipher, &"x".repeat(70_000)).is_err());
+    }
+}
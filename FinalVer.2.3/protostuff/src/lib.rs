@@ -0,0 +1,7 @@
+// Everything the client, the server and the lobby have to agree on: the lobby's request
+// lines, the handshake and the key it yields, and the encrypted frames after it.
+// A protocol change belongs here so all three pick it up together.
+
+pub mod auth;
+pub mod frame;
+pub mod lobby;
@@ -0,0 +1,145 @@
+use std::fmt;
+
+pub const LOBBY_PORT: u16 = 8080;
+pub const SERVER_PORT: u16 = 8081;
+pub const INVITE_REGISTERED: &str = "Invite registered";
+
+// Every connection to the lobby carries one request line:
+//   "server <ip> <open|locked> <name>"                          a server announcing itself
+//   "invite <code> <ip> <room> <until|never> <uses|unlimited>"  a server registering an invite
+//   "client <name> [invite code]"                               a client looking for a server
+// Clients get a Listing back, or a line saying why not. Invites are answered with
+// INVITE_REGISTERED or the reason they weren't.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Server(ServerEntry),
+    Invite(InviteEntry),
+    Client { name: String, code: Option<String> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerEntry {
+    pub ip: String,
+    pub locked: bool, // needs a password to join
+    pub name: String,
+}
+
+// Minted by a server, resolved by the lobby so the client lands on that server and room
+#[derive(Debug, Clone, PartialEq)]
+pub struct InviteEntry {
+    pub code: String,
+    pub ip: String,
+    pub room: String,
+    pub until: Option<u64>, // unix seconds, None never expires
+    pub uses: Option<u32>,  // None for unlimited
+}
+
+// "<ip>:8081 <open|locked> [room]", the room when the client came with an invite
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    pub addr: String,
+    pub locked: bool,
+    pub room: Option<String>,
+}
+
+impl Request {
+    pub fn parse(line: &str) -> Option<Request> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts[..] {
+            ["server", ip, access, ref name @ ..] if !name.is_empty() => {
+                Some(Request::Server(ServerEntry { ip: ip.to_string(), locked: access == "locked", name: name.join(" ") }))
+            }
+            ["invite", code, ip, room, until, uses] => Some(Request::Invite(InviteEntry {
+                code: code.to_string(),
+                ip: ip.to_string(),
+                room: room.to_string(),
+                until: match until {
+                    "never" => None,
+                    until => Some(until.parse().ok()?),
+                },
+                uses: match uses {
+                    "unlimited" => None,
+                    uses => Some(uses.parse().ok()?),
+                },
+            })),
+            ["client", name] => Some(Request::Client { name: name.to_string(), code: None }),
+            ["client", name, code] => Some(Request::Client { name: name.to_string(), code: Some(code.to_string()) }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Request::Server(entry) => write!(f, "server {} {} {}", entry.ip, access(entry.locked), entry.name),
+            Request::Invite(entry) => {
+                let until = entry.until.map_or("never".to_string(), |until| until.to_string());
+                let uses = entry.uses.map_or("unlimited".to_string(), |uses| uses.to_string());
+                write!(f, "invite {} {} {} {} {}", entry.code, entry.ip, entry.room, until, uses)
+            }
+            Request::Client { name, code: None } => write!(f, "client {}", name),
+            Request::Client { name, code: Some(code) } => write!(f, "client {} {}", name, code),
+        }
+    }
+}
+
+impl Listing {
+    pub fn new(ip: &str, locked: bool, room: Option<&str>) -> Listing {
+        Listing { addr: format!("{}:{}", ip, SERVER_PORT), locked, room: room.map(str::to_string) }
+    }
+
+    // None when the lobby answered with a reason instead
+    pub fn parse(line: &str) -> Option<Listing> {
+        let mut words = line.split_whitespace();
+        let addr = words.next().filter(|addr| addr.contains(':'))?.to_string();
+        let locked = words.next() == Some("locked");
+        let room = words.next().map(str::to_string);
+        Some(Listing { addr, locked, room })
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.addr, access(self.locked))?;
+        if let Some(room) = &self.room {
+            write!(f, " {}", room)?;
+        }
+        Ok(())
+    }
+}
+
+fn access(locked: bool) -> &'static str {
+    if locked { "locked" } else { "open" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_round_trip() {
+        let requests = [
+            Request::Server(ServerEntry { ip: "10.0.0.2".to_string(), locked: true, name: "my cool server".to_string() }),
+            Request::Invite(InviteEntry { code: "ABCD-EFGH".to_string(), ip: "10.0.0.2".to_string(), room: "ops".to_string(), until: Some(1800000000), uses: None }),
+            Request::Invite(InviteEntry { code: "ABCD-EFGH".to_string(), ip: "10.0.0.2".to_string(), room: "ops".to_string(), until: None, uses: Some(3) }),
+            Request::Client { name: "bob".to_string(), code: None },
+            Request::Client { name: "bob".to_string(), code: Some("ABCD-EFGH".to_string()) },
+        ];
+        for request in requests {
+            assert_eq!(Request::parse(&format!("{}\n", request)), Some(request));
+        }
+        assert_eq!(Request::parse("server 10.0.0.2 open"), None);
+        assert_eq!(Request::parse("invite ABCD-EFGH 10.0.0.2 ops soon 3"), None);
+        assert_eq!(Request::parse("hello"), None);
+    }
+
+    #[test]
+    fn listings_and_refusals() {
+        let listing = Listing::new("10.0.0.2", false, Some("ops"));
+        assert_eq!(listing.to_string(), "10.0.0.2:8081 open ops");
+        assert_eq!(Listing::parse("10.0.0.2:8081 open ops\n"), Some(listing));
+        assert_eq!(Listing::parse("10.0.0.2:8081 locked").map(|l| (l.locked, l.room)), Some((true, None)));
+        assert_eq!(Listing::parse("No servers available\n"), None);
+    }
+}
@@ -0,0 +1,151 @@
+use std::io::{self, ErrorKind};
+use nameless_proto::auth::{self, AESKey, Greeting, UserKey};
+use rand::Rng;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const LINE_MAX: u64 = 1024; // the intro and the handshake replies are far shorter
+
+// The room key and, on a password protected server, the salt it was derived with.
+// Open servers hand the key out, locked ones only prove they share it, see the
+// handshake lines in nameless_proto::auth.
+pub struct ServerKey {
+    pub key: AESKey,
+    pub salt: Option<[u8; 16]>,
+}
+
+impl ServerKey {
+    pub fn random() -> ServerKey {
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill(&mut key);
+        ServerKey { key, salt: None }
+    }
+
+    pub fn from_password(password: &str) -> ServerKey {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill(&mut salt);
+        ServerKey { key: auth::derive_key(password, &salt), salt: Some(salt) }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.salt.is_some()
+    }
+}
+
+// Runs the server side of the handshake. Ok(false) means the client didn't know the password.
+pub async fn handshake(writer: &mut (impl AsyncWrite + Unpin), reader: &mut (impl AsyncBufRead + Unpin), server_key: &ServerKey) -> io::Result<bool> {
+    let Some(salt) = server_key.salt else {
+        writer.write_all(format!("{}\n", Greeting::Key(server_key.key)).as_bytes()).await?;
+        return Ok(true);
+    };
+
+    let mut challenge = [0u8; 32];
+    rand::thread_rng().fill(&mut challenge);
+    let greeting = Greeting::Auth { salt: salt.to_vec(), challenge: challenge.to_vec() };
+    writer.write_all(format!("{}\n", greeting).as_bytes()).await?;
+
+    let reply = read_line(reader).await?;
+    if !auth::check_proof(&server_key.key, &challenge, &reply) {
+        writer.write_all(format!("{}\n", auth::DENIED).as_bytes()).await?;
+        return Ok(false);
+    }
+    writer.write_all(format!("{}\n", auth::answer(&server_key.key, &challenge)).as_bytes()).await?;
+    Ok(true)
+}
+
+// Asks who the client is once it has the room key. None when its signature doesn't hold up.
+pub async fn identify(writer: &mut (impl AsyncWrite + Unpin), reader: &mut (impl AsyncBufRead + Unpin), server_key: &ServerKey) -> io::Result<Option<UserKey>> {
+    let mut challenge = [0u8; 32];
+    rand::thread_rng().fill(&mut challenge);
+    writer.write_all(format!("{}\n", auth::who(&challenge)).as_bytes()).await?;
+
+    let reply = read_line(reader).await?;
+    Ok(auth::check_identity(&server_key.key, &challenge, &reply))
+}
+
+// One line of the intro or the handshake. Whoever sends LINE_MAX bytes without a newline
+// isn't a client, so it's cut off there instead of buffering whatever they send.
+pub async fn read_line(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<String> {
+    let mut line = String::new();
+    let read = reader.take(LINE_MAX).read_line(&mut line).await?;
+    if read == 0 {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof, "client left during the handshake"));
+    }
+    if read as u64 == LINE_MAX && !line.ends_with('\n') {
+        return Err(io::Error::new(ErrorKind::InvalidData, "handshake line too long"));
+    }
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{BufReader, DuplexStream, duplex, split};
+
+    // The client's side of the password handshake, Ok once both sides proved the key
+    async fn log_in(client: DuplexStream, password: &str) -> io::Result<()> {
+        let (read, mut write) = split(client);
+        let mut read = BufReader::new(read);
+        let Greeting::Auth { salt, challenge } = Greeting::parse(&read_line(&mut read).await?)? else {
+            panic!("a locked server sent the key");
+        };
+        let key = auth::derive_key(password, &salt);
+        write.write_all(format!("{}\n", auth::proof(&key, &challenge)).as_bytes()).await?;
+        auth::check_answer(&key, &challenge, &read_line(&mut read).await?)
+    }
+
+    // The client's answer to "who", signed for `key`
+    async fn introduce(client: DuplexStream, identity: &auth::Identity, key: &AESKey) -> io::Result<()> {
+        let (read, mut write) = split(client);
+        let who = read_line(&mut BufReader::new(read)).await?;
+        write.write_all(format!("{}\n", auth::identify(identity, key, &who)?).as_bytes()).await
+    }
+
+    #[tokio::test]
+    async fn open_servers_hand_out_the_key() {
+        let server_key = ServerKey::random();
+        let mut sent = Vec::new();
+        assert!(handshake(&mut sent, &mut "".as_bytes(), &server_key).await.unwrap());
+        let greeting = Greeting::parse(&String::from_utf8(sent).unwrap()).unwrap();
+        assert_eq!(greeting, Greeting::Key(server_key.key));
+    }
+
+    #[tokio::test]
+    async fn locked_servers_only_let_the_password_in() {
+        let server_key = ServerKey::from_password("hunter2");
+        for (password, let_in) in [("hunter2", true), ("hunter3", false)] {
+            let (client, server) = duplex(1024);
+            let (read, mut write) = split(server);
+            let mut read = BufReader::new(read);
+            let (server_side, client_side) = tokio::join!(handshake(&mut write, &mut read, &server_key), log_in(client, password));
+            assert_eq!(server_side.unwrap(), let_in);
+            assert_eq!(client_side.is_ok(), let_in, "{:?}", client_side);
+        }
+    }
+
+    #[tokio::test]
+    async fn identify_checks_the_signature() {
+        let server_key = ServerKey::random();
+        let identity = auth::new_identity();
+        // Signed for this room's key, and for another room's
+        for (key, known) in [(server_key.key, true), ([9u8; 32], false)] {
+            let (client, server) = duplex(1024);
+            let (read, mut write) = split(server);
+            let mut read = BufReader::new(read);
+            let (server_side, client_side) = tokio::join!(identify(&mut write, &mut read, &server_key), introduce(client, &identity, &key));
+            client_side.unwrap();
+            let expected = known.then(|| *identity.verifying_key().as_bytes());
+            assert_eq!(server_side.unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_lines_are_capped() {
+        let mut reader = "client alice\nme x\n".as_bytes();
+        assert_eq!(read_line(&mut reader).await.unwrap(), "client alice\n");
+        assert_eq!(read_line(&mut reader).await.unwrap(), "me x\n");
+        assert_eq!(read_line(&mut reader).await.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+        let flood = "x".repeat(10 * LINE_MAX as usize);
+        assert_eq!(read_line(&mut flood.as_bytes()).await.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
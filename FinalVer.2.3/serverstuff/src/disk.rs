@@ -0,0 +1,121 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+};
+
+// Every file the server keeps is written from one thread of its own. History, mailbox,
+// users, roles, bans and motd change their in-memory state under their lock and queue the
+// write here, so a slow disk (or an fsync) never holds a tokio worker or a lock.
+// Writes land in the order they were queued. One that fails is logged and dropped,
+// the in-memory state is what clients see either way.
+#[derive(Clone)]
+pub struct Disk {
+    jobs: mpsc::Sender<Job>,
+}
+
+enum Job {
+    Append(PathBuf, String),
+    Replace(PathBuf, Vec<String>),
+    #[cfg(test)]
+    Flush(mpsc::Sender<()>),
+}
+
+impl Disk {
+    pub fn start() -> Disk {
+        let (jobs, queued) = mpsc::channel();
+        thread::spawn(move || {
+            let mut open: HashMap<PathBuf, File> = HashMap::new();
+            for job in queued {
+                match job {
+                    Job::Append(path, line) => {
+                        if let Err(e) = append(&mut open, &path, &line) {
+                            eprintln!("Failed to write to {}: {}", path.display(), e);
+                            open.remove(&path); // reopened on the next line
+                        }
+                    }
+                    Job::Replace(path, lines) => {
+                        open.remove(&path); // the handle would point at the old file
+                        if let Err(e) = replace(&path, &lines) {
+                            eprintln!("Failed to rewrite {}: {}", path.display(), e);
+                        }
+                    }
+                    #[cfg(test)]
+                    Job::Flush(done) => {
+                        done.send(()).ok();
+                    }
+                }
+            }
+        });
+        Disk { jobs }
+    }
+
+    // Adds one line to the end of the file, creating it if need be
+    pub fn append(&self, path: &Path, line: impl ToString) {
+        self.send(Job::Append(path.to_path_buf(), line.to_string()));
+    }
+
+    // Swaps the whole file for `lines`, only once they're safely on disk
+    pub fn replace(&self, path: &Path, lines: Vec<String>) {
+        self.send(Job::Replace(path.to_path_buf(), lines));
+    }
+
+    // Blocks until everything queued so far is written, not for use on a tokio worker
+    #[cfg(test)]
+    pub fn flush(&self) {
+        let (done, written) = mpsc::channel();
+        self.send(Job::Flush(done));
+        written.recv().ok();
+    }
+
+    fn send(&self, job: Job) {
+        if self.jobs.send(job).is_err() {
+            eprintln!("The disk writer has stopped, a write was lost");
+        }
+    }
+}
+
+fn append(open: &mut HashMap<PathBuf, File>, path: &Path, line: &str) -> io::Result<()> {
+    let file = match open.entry(path.to_path_buf()) {
+        Entry::Occupied(file) => file.into_mut(),
+        Entry::Vacant(slot) => slot.insert(OpenOptions::new().create(true).append(true).open(path)?),
+    };
+    writeln!(file, "{}", line)
+}
+
+fn replace(path: &Path, lines: &[String]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    for line in lines {
+        writeln!(tmp, "{}", line)?;
+    }
+    tmp.sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn writes_land_in_order() {
+        let path = env::temp_dir().join(format!("nameless-disk-{}.txt", process::id()));
+        fs::remove_file(&path).ok();
+        let disk = Disk::start();
+        disk.append(&path, "one");
+        disk.append(&path, "two");
+        disk.flush();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+
+        // Appends after a replace go to the new file, not the old handle
+        disk.replace(&path, vec!["three".to_string()]);
+        disk.append(&path, "four");
+        disk.flush();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "three\nfour\n");
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,554 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use nameless_proto::frame::Line;
+use crate::disk::Disk;
+
+pub type SharedHistory = Arc<Mutex<History>>;
+
+// One relayed room message, stored on disk as
+// "[@in_reply_to=<id>;mentions=<user>,..] <kind> <id> <ts> <room> <from> :<text>" where kind is msg or action.
+// Later changes are appended as "edit <id> <ts> :<text>", "delete <id> <ts>"
+// and "react|unreact <id> <ts> <user> <emoji>". Text spanning lines goes in an escaped
+// "@text=" tag instead of the last argument, so every record stays one line.
+// Direct messages take their ids from the same sequence, "private <id> <ts> <from> <to>"
+// only says who sent one to whom so read receipts find their way back, the text isn't kept.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub id: u64,
+    pub ts: u64, // unix seconds, UTC
+    pub room: String,
+    pub kind: String,
+    pub from: String,
+    pub text: String,
+    pub edited: Option<u64>, // when the text was last changed
+    pub deleted: bool,
+    pub reactions: BTreeMap<String, BTreeSet<String>>, // emoji -> who reacted with it
+    pub in_reply_to: Option<u64>, // parent message when this is a reply
+    pub thread: Option<u64>,      // id of the message that started the thread, replies only
+    pub mentions: Vec<String>,    // known users it @mentions
+}
+
+impl Record {
+    // The frame clients get, live or replayed: "@id=..;ts=.. msg <from> :<text>"
+    pub fn to_line(&self) -> Line {
+        let mut line = Line::new(&self.kind).tag("id", self.id).tag("ts", self.ts);
+        if let Some(edited) = self.edited {
+            line = line.tag("edited", edited);
+        }
+        if !self.reactions.is_empty() {
+            line = line.tag("reactions", self.reaction_counts());
+        }
+        if let (Some(parent), Some(thread)) = (self.in_reply_to, self.thread) {
+            line = line.tag("in_reply_to", parent).tag("thread", thread);
+        }
+        if !self.mentions.is_empty() {
+            line = line.tag("mentions", self.mentions.join(","));
+        }
+        line.arg(&self.from).arg(&self.text)
+    }
+
+    // "<emoji>:<count>,<emoji>:<count>"
+    pub fn reaction_counts(&self) -> String {
+        let counts: Vec<String> = self.reactions.iter().map(|(emoji, users)| format!("{}:{}", emoji, users.len())).collect();
+        counts.join(",")
+    }
+
+    fn to_disk(&self) -> Line {
+        let mut line = Line::new(&self.kind);
+        if let Some(parent) = self.in_reply_to {
+            line = line.tag("in_reply_to", parent);
+        }
+        if !self.mentions.is_empty() {
+            line = line.tag("mentions", self.mentions.join(","));
+        }
+        with_text(line.arg(self.id.to_string()).arg(self.ts.to_string()).arg(&self.room).arg(&self.from), &self.text)
+    }
+
+    // The thread root is worked out by History::open, parents are always loaded first
+    fn from_disk(line: &Line) -> Option<Record> {
+        Some(Record {
+            id: line.get(0)?.parse().ok()?,
+            ts: line.get(1)?.parse().ok()?,
+            room: line.get(2)?.to_string(),
+            kind: line.cmd.clone(),
+            from: line.get(3)?.to_string(),
+            text: disk_text(line, 4)?.to_string(),
+            edited: None,
+            deleted: false,
+            reactions: BTreeMap::new(),
+            in_reply_to: match line.get_tag("in_reply_to") {
+                Some(parent) => Some(parent.parse().ok()?),
+                None => None,
+            },
+            thread: None,
+            mentions: line.get_tag("mentions").map(|names| names.split(',').map(str::to_string).collect()).unwrap_or_default(),
+        })
+    }
+}
+
+// Append-only message log, everything is also kept in memory for replay
+pub struct History {
+    disk: Disk,
+    path: PathBuf,
+    records: Vec<Record>,
+    privates: HashMap<u64, (String, String)>, // direct message id -> (from, to)
+    read: HashSet<(u64, String)>,             // (id, reader) receipts already passed on
+    next_id: u64,
+}
+
+impl History {
+    pub fn open(path: &Path, disk: Disk) -> io::Result<History> {
+        let mut records = Vec::new();
+        let mut privates = HashMap::new();
+        if let Ok(existing) = File::open(path) {
+            for raw in BufReader::new(existing).lines() {
+                let raw = raw?;
+                let loaded = Line::parse(&raw).is_some_and(|line| match line.cmd.as_str() {
+                    "edit" | "delete" | "react" | "unreact" => apply_change(&mut records, &line),
+                    "private" => match (line.get(0).and_then(|id| id.parse().ok()), line.get(2), line.get(3)) {
+                        (Some(id), Some(from), Some(to)) => privates.insert(id, (from.to_string(), to.to_string())).is_none(),
+                        _ => false,
+                    },
+                    _ => Record::from_disk(&line)
+                        .map(|mut record| {
+                            record.thread = record.in_reply_to.map(|parent| thread_root(&records, parent));
+                            records.push(record);
+                        })
+                        .is_some(),
+                });
+                if !loaded {
+                    eprintln!("Skipping unreadable history line: {}", raw);
+                }
+            }
+        }
+        let next_id = records.iter().map(|r| r.id).chain(privates.keys().copied()).max().unwrap_or(0) + 1;
+        Ok(History { disk, path: path.to_path_buf(), records, privates, read: HashSet::new(), next_id })
+    }
+
+    // Stamps the message with the next id and the current time and writes it out.
+    // A reply has to point at a message that still exists in the same room.
+    pub fn append(&mut self, room: &str, kind: &str, from: &str, text: &str, in_reply_to: Option<u64>, mentions: Vec<String>) -> io::Result<Record> {
+        check_text(text)?;
+        if let Some(parent) = in_reply_to
+            && self.get(parent).is_none_or(|r| r.room != room)
+        {
+            return Err(no_such_message(parent));
+        }
+        let record = Record {
+            id: self.next_id,
+            ts: now(),
+            room: room.to_string(),
+            kind: kind.to_string(),
+            from: from.to_string(),
+            text: text.to_string(),
+            edited: None,
+            deleted: false,
+            reactions: BTreeMap::new(),
+            in_reply_to,
+            thread: in_reply_to.map(|parent| thread_root(&self.records, parent)),
+            mentions,
+        };
+        self.disk.append(&self.path, record.to_disk());
+        self.next_id += 1;
+        self.records.push(record.clone());
+        Ok(record)
+    }
+
+    // The id for a direct message from `from` to `to`
+    pub fn private(&mut self, from: &str, to: &str) -> u64 {
+        let id = self.next_id;
+        self.disk.append(&self.path, Line::new("private").arg(id.to_string()).arg(now().to_string()).arg(from).arg(to));
+        self.next_id += 1;
+        self.privates.insert(id, (from.to_string(), to.to_string()));
+        id
+    }
+
+    // Who to tell that `reader` (now in `room`) has read message `id`. A room message can
+    // only be read from its room and a direct message only by whoever it went to, anything
+    // else is NotFound. Ok(None) for a receipt that was already passed on, or one's own message.
+    pub fn mark_read(&mut self, id: u64, reader: &str, room: &str) -> io::Result<Option<String>> {
+        let author = match (self.get(id), self.privates.get(&id)) {
+            (Some(record), _) if record.room == room => record.from.clone(),
+            (None, Some((from, to))) if to == reader => from.clone(),
+            _ => return Err(no_such_message(id)),
+        };
+        if author == reader || !self.read.insert((id, reader.to_string())) {
+            return Ok(None);
+        }
+        Ok(Some(author))
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Record> {
+        position(&self.records, id).map(|i| &self.records[i])
+    }
+
+    pub fn edit(&mut self, id: u64, text: &str) -> io::Result<Record> {
+        check_text(text)?;
+        let index = position(&self.records, id).ok_or_else(|| no_such_message(id))?;
+        let ts = now();
+        self.disk.append(&self.path, with_text(Line::new("edit").arg(id.to_string()).arg(ts.to_string()), text));
+        let record = &mut self.records[index];
+        record.text = text.to_string();
+        record.edited = Some(ts);
+        Ok(record.clone())
+    }
+
+    pub fn delete(&mut self, id: u64) -> io::Result<Record> {
+        let index = position(&self.records, id).ok_or_else(|| no_such_message(id))?;
+        self.disk.append(&self.path, Line::new("delete").arg(id.to_string()).arg(now().to_string()));
+        let record = &mut self.records[index];
+        record.deleted = true;
+        Ok(record.clone())
+    }
+
+    // Adds or removes `user`'s `emoji` on a message. Returns the record and whether
+    // anything changed, reacting twice with the same emoji is a no-op.
+    pub fn react(&mut self, id: u64, user: &str, emoji: &str, add: bool) -> io::Result<(Record, bool)> {
+        let index = position(&self.records, id).ok_or_else(|| no_such_message(id))?;
+        let record = &self.records[index];
+        let has_reacted = record.reactions.get(emoji).is_some_and(|users| users.contains(user));
+        if has_reacted == add {
+            return Ok((record.clone(), false));
+        }
+
+        let cmd = if add { "react" } else { "unreact" };
+        self.disk.append(&self.path, Line::new(cmd).arg(id.to_string()).arg(now().to_string()).arg(user).arg(emoji));
+        let record = &mut self.records[index];
+        set_reaction(record, user, emoji, add);
+        Ok((record.clone(), true))
+    }
+
+    // The message that started the thread `id` belongs to, followed by every reply to it
+    // (replies to replies included) in the order they were sent. Deleted messages are left out.
+    pub fn thread(&self, id: u64) -> Option<Vec<Record>> {
+        let root = thread_root(&self.records, id);
+        let first = self.get(root)?.clone();
+        let mut found = vec![first];
+        found.extend(self.records.iter().filter(|r| r.thread == Some(root) && !r.deleted).cloned());
+        Some(found)
+    }
+
+    pub fn last(&self, room: &str, count: usize) -> Vec<Record> {
+        let mut found: Vec<Record> =
+            self.records.iter().rev().filter(|r| r.room == room && !r.deleted).take(count).cloned().collect();
+        found.reverse();
+        found
+    }
+
+    // Newest first, at most `query.limit` of them, and where to carry on if older ones are
+    // left. Looks at no more than `query.scan` messages so one search can't hold the lock
+    // over the whole history; when it stops early the cursor is the last one it looked at.
+    pub fn search(&self, query: &SearchQuery) -> (Vec<Record>, Option<u64>) {
+        let end = match query.before {
+            Some(before) => self.records.partition_point(|r| r.id < before),
+            None => self.records.len(),
+        };
+        let start = end.saturating_sub(query.scan);
+        let mut found = Vec::new();
+        for record in self.records[start..end].iter().rev() {
+            if record.deleted || !query.matches(record) {
+                continue;
+            }
+            if found.len() == query.limit {
+                let oldest = found.last().map(|r: &Record| r.id);
+                return (found, oldest);
+            }
+            found.push(record.clone());
+        }
+        let more = (start > 0).then(|| self.records[start].id);
+        (found, more)
+    }
+
+    // Everything after `id`, capped to the newest `count` so a stale id can't flood the client
+    pub fn since(&self, room: &str, id: u64, count: usize) -> Vec<Record> {
+        let mut found = self.last(room, count);
+        found.retain(|r| r.id > id);
+        found
+    }
+}
+
+// A search over room messages. Words match case-insensitively anywhere in the text and
+// all have to be there. Times are unix seconds, `since` inclusive and `until` exclusive.
+// `before` is the paging cursor: only messages older than that id.
+pub struct SearchQuery {
+    pub words: Vec<String>,
+    pub room: Option<String>,
+    pub from: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub before: Option<u64>,
+    pub limit: usize,
+    pub scan: usize, // most messages looked at for one page
+}
+
+impl SearchQuery {
+    fn matches(&self, record: &Record) -> bool {
+        if self.before.is_some_and(|before| record.id >= before)
+            || self.room.as_ref().is_some_and(|room| *room != record.room)
+            || self.from.as_ref().is_some_and(|from| *from != record.from)
+            || self.since.is_some_and(|since| record.ts < since)
+            || self.until.is_some_and(|until| record.ts >= until)
+        {
+            return false;
+        }
+        let text = record.text.to_lowercase();
+        self.words.iter().all(|word| text.contains(word.as_str()))
+    }
+}
+
+// Up to `width` characters of `text` around the first of `words` found in it, "…" where cut
+pub fn excerpt(text: &str, words: &[String], width: usize) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let lower = text.to_lowercase();
+    // lowercasing can change byte lengths, so find the match as a char position in the lowercase text
+    let hit = words
+        .iter()
+        .filter_map(|word| lower.find(word.as_str()).map(|at| lower[..at].chars().count()))
+        .min()
+        .unwrap_or(0);
+    let start = hit.saturating_sub(width / 3).min(chars.len().saturating_sub(width));
+    let end = (start + width).min(chars.len());
+    let mut out: String = chars[start..end].iter().map(|&c| if c == '\n' { ' ' } else { c }).collect();
+    if start > 0 {
+        out.insert(0, '…');
+    }
+    if end < chars.len() {
+        out.push('…');
+    }
+    out
+}
+
+// ids only ever grow, so the log is sorted by id. Deleted messages count as gone.
+fn position(records: &[Record], id: u64) -> Option<usize> {
+    records.binary_search_by_key(&id, |r| r.id).ok().filter(|&i| !records[i].deleted)
+}
+
+// Replies carry their thread's root, so one hop is enough. Falls back to `id` itself
+// when the message isn't a reply (or is unknown).
+fn thread_root(records: &[Record], id: u64) -> u64 {
+    records
+        .binary_search_by_key(&id, |r| r.id)
+        .ok()
+        .and_then(|i| records[i].thread)
+        .unwrap_or(id)
+}
+
+// Newlines are fine, code blocks need them and the log escapes them. NUL can't get to
+// the UI (C strings end there) and a carriage return would rewrite the line it's on.
+pub fn check_text(text: &str) -> io::Result<()> {
+    if text.contains(['\0', '\r']) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "messages can't contain NUL or carriage returns"));
+    }
+    Ok(())
+}
+
+// `text` as the last argument, or in the "text" tag when it spans lines
+fn with_text(line: Line, text: &str) -> Line {
+    match text.contains('\n') {
+        true => line.tag("text", text).arg(""),
+        false => line.arg(text),
+    }
+}
+
+fn disk_text(line: &Line, index: usize) -> Option<&str> {
+    line.get_tag("text").or(line.get(index))
+}
+
+fn no_such_message(id: u64) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no message with id {}", id))
+}
+
+fn set_reaction(record: &mut Record, user: &str, emoji: &str, add: bool) {
+    if add {
+        record.reactions.entry(emoji.to_string()).or_default().insert(user.to_string());
+    } else if let Some(users) = record.reactions.get_mut(emoji) {
+        users.remove(user);
+        if users.is_empty() {
+            record.reactions.remove(emoji);
+        }
+    }
+}
+
+// Replays an edit, delete, react or unreact line from disk
+fn apply_change(records: &mut [Record], line: &Line) -> bool {
+    let (Some(id), Some(ts)) = (line.get(0).and_then(|id| id.parse().ok()), line.get(1).and_then(|ts| ts.parse().ok())) else {
+        return false;
+    };
+    let Some(index) = position(records, id) else {
+        return true; // already gone, nothing to apply
+    };
+    let record = &mut records[index];
+    match (line.cmd.as_str(), line.get(2), line.get(3)) {
+        ("edit", _, _) if let Some(text) = disk_text(line, 2) => {
+            record.text = text.to_string();
+            record.edited = Some(ts);
+        }
+        ("delete", _, _) => record.deleted = true,
+        ("react", Some(user), Some(emoji)) => set_reaction(record, user, emoji, true),
+        ("unreact", Some(user), Some(emoji)) => set_reaction(record, user, emoji, false),
+        _ => return false,
+    }
+    true
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    fn temp_log(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("nameless-history-{}-{}.log", name, process::id()));
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    #[test]
+    fn changes_survive_a_reload() {
+        let path = temp_log("reload");
+        let disk = Disk::start();
+        let mut history = History::open(&path, disk.clone()).unwrap();
+        let first = history.append("lobby", "msg", "alice", "hello there", None, Vec::new()).unwrap();
+        let reply = history.append("lobby", "msg", "bob", "hi @alice", Some(first.id), vec!["alice".to_string()]).unwrap();
+        let other = history.append("games", "action", "bob", "waves", None, Vec::new()).unwrap();
+        assert_eq!((first.id, reply.id, other.id), (1, 2, 3));
+        assert_eq!(reply.thread, Some(first.id));
+        history.edit(first.id, "hello everyone").unwrap();
+        history.react(reply.id, "alice", "👍", true).unwrap();
+        history.delete(other.id).unwrap();
+        assert_eq!(history.append("games", "msg", "bob", "reply to gone", Some(other.id), Vec::new()).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(history.append("games", "msg", "bob", "wrong room", Some(first.id), Vec::new()).unwrap_err().kind(), io::ErrorKind::NotFound);
+        drop(history);
+        disk.flush();
+
+        let mut history = History::open(&path, disk.clone()).unwrap();
+        let lobby = history.last("lobby", 10);
+        assert_eq!(lobby.iter().map(|r| r.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(lobby[0].text, "hello everyone");
+        assert!(lobby[0].edited.is_some());
+        assert_eq!(lobby[1].reaction_counts(), "👍:1");
+        assert_eq!(lobby[1].thread, Some(1));
+        assert_eq!(lobby[1].mentions, vec!["alice"]);
+        assert!(history.get(other.id).is_none() && history.last("games", 10).is_empty());
+        // ids keep counting past the deleted one, and direct messages share them
+        assert_eq!(history.private("alice", "bob"), 4);
+        drop(history);
+        disk.flush();
+        let mut history = History::open(&path, disk.clone()).unwrap();
+        assert_eq!(history.mark_read(4, "carol", "lobby").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(history.mark_read(4, "bob", "games").unwrap().as_deref(), Some("alice"));
+        assert!(history.get(4).is_none());
+        assert_eq!(history.append("lobby", "msg", "alice", "again", None, Vec::new()).unwrap().id, 5);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn multi_line_messages_survive_a_reload() {
+        let path = temp_log("lines");
+        let disk = Disk::start();
+        let mut history = History::open(&path, disk.clone()).unwrap();
+        let code = "look:\n```\nfn main() {\n\tprintln!(\"hi\");\n}\n```";
+        let first = history.append("lobby", "msg", "alice", code, None, Vec::new()).unwrap();
+        let second = history.append("lobby", "msg", "alice", "one line", None, Vec::new()).unwrap();
+        history.edit(second.id, "now\ntwo lines; with a \\ too").unwrap();
+        history.append("lobby", "msg", "alice", "a\nmsg 99 0 lobby bob :forged", None, Vec::new()).unwrap();
+        drop(history);
+        disk.flush();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 4);
+
+        let history = History::open(&path, disk.clone()).unwrap();
+        let lobby = history.last("lobby", 10);
+        assert_eq!(lobby.iter().map(|r| r.id).collect::<Vec<_>>(), vec![first.id, second.id, 3]);
+        assert_eq!(lobby[0].text, code);
+        assert_eq!(lobby[1].text, "now\ntwo lines; with a \\ too");
+        assert_eq!(lobby[2].text, "a\nmsg 99 0 lobby bob :forged");
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn receipts_come_from_the_room_once() {
+        let path = temp_log("read");
+        let disk = Disk::start();
+        let mut history = History::open(&path, disk.clone()).unwrap();
+        let record = history.append("lobby", "msg", "alice", "hello", None, Vec::new()).unwrap();
+        assert_eq!(history.mark_read(record.id, "bob", "lobby").unwrap().as_deref(), Some("alice"));
+        assert_eq!(history.mark_read(record.id, "bob", "lobby").unwrap(), None);
+        assert_eq!(history.mark_read(record.id, "alice", "lobby").unwrap(), None);
+        assert_eq!(history.mark_read(record.id, "mallory", "games").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(history.mark_read(99, "bob", "lobby").unwrap_err().kind(), io::ErrorKind::NotFound);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn since_only_sends_newer_and_at_most_count() {
+        let path = temp_log("since");
+        let disk = Disk::start();
+        let mut history = History::open(&path, disk.clone()).unwrap();
+        for n in 1..=5 {
+            history.append("lobby", "msg", "alice", &format!("message {}", n), None, Vec::new()).unwrap();
+        }
+        history.append("games", "msg", "bob", "elsewhere", None, Vec::new()).unwrap();
+        let ids = |records: Vec<Record>| records.iter().map(|r| r.id).collect::<Vec<_>>();
+        assert_eq!(ids(history.since("lobby", 3, 10)), vec![4, 5]);
+        assert_eq!(ids(history.since("lobby", 0, 2)), vec![4, 5]);
+        assert!(history.since("lobby", 5, 10).is_empty());
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn search_pages_and_stops_scanning_early() {
+        let path = temp_log("search");
+        let disk = Disk::start();
+        let mut history = History::open(&path, disk.clone()).unwrap();
+        for n in 1..=10 {
+            let text = if n % 2 == 0 { format!("Pizza {}", n) } else { format!("pasta {}", n) };
+            history.append("lobby", "msg", "alice", &text, None, Vec::new()).unwrap();
+        }
+        let query = |before, limit, scan| SearchQuery {
+            words: vec!["pizza".to_string()],
+            room: None,
+            from: None,
+            since: None,
+            until: None,
+            before,
+            limit,
+            scan,
+        };
+        let ids = |(records, more): (Vec<Record>, Option<u64>)| (records.iter().map(|r| r.id).collect::<Vec<_>>(), more);
+        assert_eq!(ids(history.search(&query(None, 2, 100))), (vec![10, 8], Some(8)));
+        assert_eq!(ids(history.search(&query(Some(8), 5, 100))), (vec![6, 4, 2], None));
+        // Only the newest three get looked at, the cursor picks up after them
+        assert_eq!(ids(history.search(&query(None, 5, 3))), (vec![10, 8], Some(8)));
+        assert_eq!(ids(history.search(&query(Some(8), 5, 3))), (vec![6], Some(5)));
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn nul_and_carriage_returns_never_reach_the_log() {
+        let path = temp_log("control");
+        let disk = Disk::start();
+        let mut history = History::open(&path, disk.clone()).unwrap();
+        let forged = "hi\rmsg 99 0 lobby bob :forged";
+        assert_eq!(history.append("lobby", "msg", "mallory", forged, None, Vec::new()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let record = history.append("lobby", "msg", "mallory", "hi", None, Vec::new()).unwrap();
+        assert_eq!(history.edit(record.id, forged).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(history.append("lobby", "msg", "mallory", "nul\0", None, Vec::new()).is_err());
+        drop(history);
+        disk.flush();
+
+        let history = History::open(&path, disk.clone()).unwrap();
+        let lobby = history.last("lobby", 10);
+        assert_eq!(lobby.len(), 1);
+        assert_eq!(lobby[0].text, "hi");
+        fs::remove_file(&path).ok();
+    }
+}
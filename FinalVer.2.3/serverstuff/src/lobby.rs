@@ -0,0 +1,57 @@
+use std::io;
+use nameless_proto::lobby::{INVITE_REGISTERED, InviteEntry, Request, ServerEntry};
+use rand::Rng;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+// Letters and digits that can't be mistaken for each other when read out or typed
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 8;
+const REPLY_MAX: u64 = 1024;
+
+// Where this server registered itself. Every message to the lobby is one line on a
+// fresh connection, the lobby keeps the server list and the invites.
+pub struct Lobby {
+    pub addr: String,
+    pub serv_ip: String,
+}
+
+impl Lobby {
+    async fn send(&self, request: Request) -> io::Result<()> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        stream.write_all(format!("{}\n", request).as_bytes()).await?;
+        stream.shutdown().await.ok();
+        Ok(())
+    }
+
+    // The same, for requests the lobby answers with a line
+    async fn ask(&self, request: Request) -> io::Result<String> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        stream.write_all(format!("{}\n", request).as_bytes()).await?;
+        let mut reply = String::new();
+        BufReader::new(stream.take(REPLY_MAX)).read_line(&mut reply).await?;
+        Ok(reply.trim_end().to_string())
+    }
+
+    pub async fn register(&self, name: &str, locked: bool) -> io::Result<()> {
+        self.send(Request::Server(ServerEntry { ip: self.serv_ip.clone(), locked, name: name.to_string() })).await
+    }
+
+    // The lobby counts the uses. InvalidData when it refused the invite, with its reason.
+    pub async fn register_invite(&self, code: &str, room: &str, until: Option<u64>, uses: Option<u32>) -> io::Result<()> {
+        let entry = InviteEntry { code: code.to_string(), ip: self.serv_ip.clone(), room: room.to_string(), until, uses };
+        match self.ask(Request::Invite(entry)).await? {
+            reply if reply == INVITE_REGISTERED => Ok(()),
+            reply => Err(io::Error::new(io::ErrorKind::InvalidData, reply)),
+        }
+    }
+}
+
+// "ABCD-EFGH"
+pub fn new_invite_code() -> String {
+    let mut rng = rand::thread_rng();
+    let code: String = (0..CODE_LENGTH).map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char).collect();
+    format!("{}-{}", &code[..CODE_LENGTH / 2], &code[CODE_LENGTH / 2..])
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use nameless_proto::frame::Line;
+use crate::disk::Disk;
+use crate::history::now;
+
+pub type SharedMailbox = Arc<Mutex<Mailbox>>;
+
+pub const MAILBOX_CAP: usize = 100; // per user, the oldest frame goes first
+pub const MAILBOX_EXPIRY_SECS: u64 = 7 * 24 * 60 * 60;
+const COMPACT_MIN_LINES: usize = 1000;
+
+// Frames held for registered users while they're offline, see users.rs for who is.
+// The mailbox file is a log: "queued <user> <ts> :<frame>" when one comes in (the frame goes
+// in an escaped "@frame=" tag instead when it spans lines) and
+// "taken <user>" once they've had everything before it. It's compacted on open and
+// whenever it has grown to several times what's still waiting.
+pub struct Mailbox {
+    mailbox_path: PathBuf,
+    disk: Disk,
+    queues: HashMap<String, VecDeque<(u64, Line)>>,
+    logged: usize, // lines in the file
+}
+
+impl Mailbox {
+    pub fn open(mailbox_path: &Path, disk: Disk) -> io::Result<Mailbox> {
+        let mut queues: HashMap<String, VecDeque<(u64, Line)>> = HashMap::new();
+        if let Ok(existing) = File::open(mailbox_path) {
+            for line in BufReader::new(existing).lines() {
+                let line = line?;
+                let entry = Line::parse(&line).and_then(|logged| match (logged.cmd.as_str(), logged.get(0)) {
+                    ("taken", Some(user)) => {
+                        queues.remove(user);
+                        Some(())
+                    }
+                    ("queued", Some(user)) => {
+                        let ts: u64 = logged.get(1)?.parse().ok()?;
+                        let queue = queues.entry(user.to_string()).or_default();
+                        queue.push_back((ts, Line::parse(logged.get_tag("frame").or(logged.get(2))?)?));
+                        if queue.len() > MAILBOX_CAP {
+                            queue.pop_front();
+                        }
+                        Some(())
+                    }
+                    _ => None,
+                });
+                if entry.is_none() {
+                    eprintln!("Skipping unreadable mailbox line: {}", line);
+                }
+            }
+        }
+
+        let mut mailbox = Mailbox { mailbox_path: mailbox_path.to_path_buf(), disk, queues, logged: 0 };
+        mailbox.expire();
+        mailbox.compact();
+        Ok(mailbox)
+    }
+
+    pub fn enqueue(&mut self, username: &str, frame: Line) {
+        let ts = now();
+        self.disk.append(&self.mailbox_path, queued_entry(username, ts, &frame));
+        self.logged += 1;
+
+        let queue = self.queues.entry(username.to_string()).or_default();
+        queue.push_back((ts, frame));
+        while queue.len() > MAILBOX_CAP {
+            queue.pop_front();
+        }
+        self.compact_if_grown();
+    }
+
+    // Everything still waiting for `username`, oldest first, and empties their queue
+    pub fn take(&mut self, username: &str) -> Vec<Line> {
+        self.expire();
+        let Some(queue) = self.queues.remove(username) else {
+            return Vec::new();
+        };
+        self.disk.append(&self.mailbox_path, Line::new("taken").arg(username));
+        self.logged += 1;
+        self.compact_if_grown();
+        queue.into_iter().map(|(_, frame)| frame).collect()
+    }
+
+    fn expire(&mut self) {
+        let cutoff = now().saturating_sub(MAILBOX_EXPIRY_SECS);
+        for queue in self.queues.values_mut() {
+            queue.retain(|(ts, _)| *ts >= cutoff);
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+    }
+
+    fn compact_if_grown(&mut self) {
+        let waiting: usize = self.queues.values().map(VecDeque::len).sum();
+        if self.logged > COMPACT_MIN_LINES && self.logged > 4 * waiting {
+            self.expire();
+            self.compact();
+        }
+    }
+
+    // Writes out only what's still waiting and carries on appending to that
+    fn compact(&mut self) {
+        let mut lines = Vec::new();
+        for (user, queue) in &self.queues {
+            for (ts, frame) in queue {
+                lines.push(queued_entry(user, *ts, frame).to_string());
+            }
+        }
+        self.logged = lines.len();
+        self.disk.replace(&self.mailbox_path, lines);
+    }
+}
+
+fn queued_entry(username: &str, ts: u64, frame: &Line) -> Line {
+    let entry = Line::new("queued");
+    let frame = frame.to_string();
+    match frame.contains('\n') {
+        true => entry.tag("frame", frame).arg(username).arg(ts.to_string()).arg(""),
+        false => entry.arg(username).arg(ts.to_string()).arg(frame),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    fn dm(text: &str) -> Line {
+        Line::new("dm").arg("bob").arg(text)
+    }
+
+    #[test]
+    fn queues_survive_a_reload_until_taken() {
+        let path = env::temp_dir().join(format!("nameless-mailbox-{}.log", process::id()));
+        fs::remove_file(&path).ok();
+        let disk = Disk::start();
+        let mut mailbox = Mailbox::open(&path, disk.clone()).unwrap();
+        mailbox.enqueue("alice", dm("first"));
+        mailbox.enqueue("alice", dm("second\nqueued carol 0 :forged"));
+        mailbox.enqueue("carol", dm("for carol"));
+        drop(mailbox);
+        disk.flush();
+
+        let mut mailbox = Mailbox::open(&path, disk.clone()).unwrap();
+        assert_eq!(mailbox.take("alice"), vec![dm("first"), dm("second\nqueued carol 0 :forged")]);
+        assert!(mailbox.take("alice").is_empty());
+        drop(mailbox);
+        disk.flush();
+
+        // What was taken stays taken, the rest stays queued
+        let mut mailbox = Mailbox::open(&path, disk.clone()).unwrap();
+        assert!(mailbox.take("alice").is_empty());
+        assert_eq!(mailbox.take("carol"), vec![dm("for carol")]);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn queues_keep_the_newest_and_the_file_stays_small() {
+        let path = env::temp_dir().join(format!("nameless-mailbox-cap-{}.log", process::id()));
+        fs::remove_file(&path).ok();
+        let disk = Disk::start();
+        let mut mailbox = Mailbox::open(&path, disk.clone()).unwrap();
+        for n in 0..3 * COMPACT_MIN_LINES {
+            mailbox.enqueue("alice", dm(&n.to_string()));
+        }
+        disk.flush();
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines <= COMPACT_MIN_LINES + 1, "{} lines", lines);
+        drop(mailbox);
+        disk.flush();
+
+        let mut mailbox = Mailbox::open(&path, disk.clone()).unwrap();
+        let queued = mailbox.take("alice");
+        assert_eq!(queued.len(), MAILBOX_CAP);
+        assert_eq!(queued.last(), Some(&dm(&(3 * COMPACT_MIN_LINES - 1).to_string())));
+        fs::remove_file(&path).ok();
+    }
+}
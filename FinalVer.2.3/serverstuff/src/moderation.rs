@@ -0,0 +1,264 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use nameless_proto::frame::Line;
+use crate::disk::Disk;
+use crate::history::now;
+
+pub type SharedModeration = Arc<Mutex<Moderation>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Role {
+    Member,
+    Moderator,
+    Owner,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Owner => "owner",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Bans go by username or by address, a username that parses as an IP is taken as one
+#[derive(Debug, Clone, PartialEq)]
+pub enum BanTarget {
+    User(String),
+    Ip(IpAddr),
+}
+
+impl BanTarget {
+    pub fn parse(target: &str) -> BanTarget {
+        match target.parse() {
+            Ok(ip) => BanTarget::Ip(ip),
+            Err(_) => BanTarget::User(target.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanTarget::User(name) => write!(f, "{}", name),
+            BanTarget::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+pub struct Ban {
+    pub target: BanTarget,
+    pub until: Option<u64>, // unix seconds, None for good
+    pub by: String,
+    pub reason: String,
+}
+
+// Who may moderate and who is kept out. Roles and bans are small, so their files are
+// rewritten on every change:
+//   roles file: "<owner|moderator> <user>", anyone not listed is a member
+//   bans file:  "ban <user|ip> <until|perm> <by> :<reason>"
+// Mutes only last as long as the server runs.
+pub struct Moderation {
+    disk: Disk,
+    roles_path: PathBuf,
+    bans_path: PathBuf,
+    roles: HashMap<String, Role>,
+    bans: Vec<Ban>,
+    mutes: HashMap<String, Option<u64>>, // user -> muted until
+}
+
+impl Moderation {
+    pub fn open(roles_path: &Path, bans_path: &Path, disk: Disk) -> io::Result<Moderation> {
+        let mut roles = HashMap::new();
+        if let Ok(existing) = File::open(roles_path) {
+            for line in BufReader::new(existing).lines() {
+                let line = line?;
+                match line.split_whitespace().collect::<Vec<_>>()[..] {
+                    ["owner", user] => roles.insert(user.to_string(), Role::Owner),
+                    ["moderator", user] => roles.insert(user.to_string(), Role::Moderator),
+                    [] => None,
+                    _ => {
+                        eprintln!("Skipping unreadable roles line: {}", line);
+                        None
+                    }
+                };
+            }
+        }
+
+        let mut bans = Vec::new();
+        if let Ok(existing) = File::open(bans_path) {
+            for line in BufReader::new(existing).lines() {
+                let line = line?;
+                let ban = Line::parse(&line).filter(|l| l.cmd == "ban").and_then(|l| {
+                    let until = match l.get(1)? {
+                        "perm" => None,
+                        until => Some(until.parse().ok()?),
+                    };
+                    Some(Ban {
+                        target: BanTarget::parse(l.get(0)?),
+                        until,
+                        by: l.get(2)?.to_string(),
+                        reason: l.get(3).unwrap_or_default().to_string(),
+                    })
+                });
+                match ban {
+                    Some(ban) => bans.push(ban),
+                    None => eprintln!("Skipping unreadable ban line: {}", line),
+                }
+            }
+        }
+
+        Ok(Moderation {
+            disk,
+            roles_path: roles_path.to_path_buf(),
+            bans_path: bans_path.to_path_buf(),
+            roles,
+            bans,
+            mutes: HashMap::new(),
+        })
+    }
+
+    pub fn role(&self, username: &str) -> Role {
+        self.roles.get(username).copied().unwrap_or(Role::Member)
+    }
+
+    pub fn has_owner(&self) -> bool {
+        self.roles.values().any(|role| *role == Role::Owner)
+    }
+
+    pub fn set_role(&mut self, username: &str, role: Role) {
+        match role {
+            Role::Member => self.roles.remove(username),
+            role => self.roles.insert(username.to_string(), role),
+        };
+        self.save_roles()
+    }
+
+    // Roles follow a renamed user, otherwise /nick would be a way to shed them or a mute
+    pub fn rename(&mut self, old: &str, new: &str) {
+        if let Some(until) = self.mutes.remove(old) {
+            self.mutes.insert(new.to_string(), until);
+        }
+        if let Some(role) = self.roles.remove(old) {
+            self.roles.insert(new.to_string(), role);
+            self.save_roles();
+        }
+    }
+
+    pub fn ban(&mut self, ban: Ban) {
+        self.bans.retain(|b| b.target != ban.target);
+        self.bans.push(ban);
+        self.save_bans()
+    }
+
+    // Returns whether there was a ban to lift
+    pub fn unban(&mut self, target: &BanTarget) -> bool {
+        let before = self.bans.len();
+        self.bans.retain(|b| b.target != *target);
+        if self.bans.len() == before {
+            return false;
+        }
+        self.save_bans();
+        true
+    }
+
+    // The ban keeping `username` (connecting from `ip`) out, if any. The owner can't be locked out.
+    pub fn ban_for(&mut self, username: &str, ip: IpAddr) -> Option<&Ban> {
+        self.expire();
+        if self.role(username) == Role::Owner {
+            return None;
+        }
+        self.bans.iter().find(|b| match &b.target {
+            BanTarget::User(name) => name == username,
+            BanTarget::Ip(banned) => *banned == ip,
+        })
+    }
+
+    pub fn is_banned_name(&self, username: &str) -> bool {
+        self.bans.iter().any(|b| b.target == BanTarget::User(username.to_string()))
+    }
+
+    pub fn mute(&mut self, username: &str, until: Option<u64>) {
+        self.mutes.insert(username.to_string(), until);
+    }
+
+    pub fn unmute(&mut self, username: &str) -> bool {
+        self.mutes.remove(username).is_some()
+    }
+
+    pub fn is_muted(&mut self, username: &str) -> bool {
+        self.expire();
+        self.mutes.contains_key(username)
+    }
+
+    fn expire(&mut self) {
+        let now = now();
+        let before = self.bans.len();
+        self.bans.retain(|b| b.until.is_none_or(|until| until > now));
+        self.mutes.retain(|_, until| until.is_none_or(|until| until > now));
+        if self.bans.len() != before {
+            self.save_bans();
+        }
+    }
+
+    fn save_roles(&self) {
+        let mut lines: Vec<String> = self.roles.iter().map(|(user, role)| format!("{} {}", role, user)).collect();
+        lines.sort();
+        self.disk.replace(&self.roles_path, lines);
+    }
+
+    fn save_bans(&self) {
+        let lines: Vec<String> = self
+            .bans
+            .iter()
+            .map(|b| {
+                let until = b.until.map_or("perm".to_string(), |until| until.to_string());
+                Line::new("ban").arg(b.target.to_string()).arg(until).arg(b.by.as_str()).arg(b.reason.as_str()).to_string()
+            })
+            .collect();
+        self.disk.replace(&self.bans_path, lines);
+    }
+}
+
+// Anything longer might as well be "perm", and keeps now() + secs from overflowing
+const MAX_DURATION_SECS: u64 = 100 * 365 * 24 * 60 * 60;
+
+// "30s", "10m", "2h", "7d" in seconds, "perm" is None
+pub fn parse_duration(text: &str) -> Option<Option<u64>> {
+    if text == "perm" {
+        return Some(None);
+    }
+    let unit = match text.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let count: u64 = text[..text.len() - 1].parse().ok()?;
+    Some(Some(count.checked_mul(unit).filter(|secs| (1..=MAX_DURATION_SECS).contains(secs))?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_stay_in_range() {
+        assert_eq!(parse_duration("perm"), Some(None));
+        assert_eq!(parse_duration("2h"), Some(Some(7200)));
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(parse_duration("99999999999999d"), None);
+        assert_eq!(parse_duration("18446744073709551615s"), None);
+    }
+}
@@ -0,0 +1,47 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use crate::disk::Disk;
+
+pub type SharedMotd = Arc<Mutex<Motd>>;
+
+// Message of the day and rules, shown to everyone who connects. The file is plain text,
+// one system frame per line, and is rewritten when the owner changes it at runtime.
+pub struct Motd {
+    disk: Disk,
+    path: PathBuf,
+    lines: Vec<String>,
+}
+
+impl Motd {
+    pub fn open(path: &Path, disk: Disk) -> io::Result<Motd> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Motd { disk, path: path.to_path_buf(), lines: split_lines(&text) })
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    // Empty text clears it, the file stays but is left empty
+    pub fn set(&mut self, text: &str) {
+        self.lines = split_lines(text);
+        self.disk.replace(&self.path, self.lines.clone());
+    }
+}
+
+// Trailing blank lines would only show up as empty system messages
+fn split_lines(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = text.lines().map(|line| line.trim_end().to_string()).collect();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    lines
+}
@@ -0,0 +1,379 @@
+use std::{
+    collections::VecDeque,
+    env,
+    io::{self, ErrorKind},
+    pin::pin,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::Notify,
+    time,
+};
+
+pub type Packet = Arc<[u8]>; // a sealed frame, shared by everyone it goes to
+
+pub static QUEUES: LazyLock<QueueSettings> = LazyLock::new(QueueSettings::from_env);
+
+// What happens to a frame for a client whose queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    Drop,       // the client misses it
+    Disconnect, // the client is dropped and catches up from its last id when it reconnects
+    Coalesce,   // state updates replace the one they outdate or are dropped, anything else disconnects
+}
+
+impl Overflow {
+    fn parse(text: &str) -> Option<Overflow> {
+        match text {
+            "drop" => Some(Overflow::Drop),
+            "disconnect" => Some(Overflow::Disconnect),
+            "coalesce" => Some(Overflow::Coalesce),
+            _ => None,
+        }
+    }
+}
+
+// Set with environment variables:
+//   NAMELESS_QUEUE     frames waiting for one client before the overflow policy kicks in
+//   NAMELESS_OVERFLOW  drop | disconnect | coalesce
+// The queue has to hold a whole catch-up on join, history since the last id plus the
+// mailbox plus a few greeting frames, so smaller values are raised to MIN_QUEUE.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueSettings {
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+pub const MIN_QUEUE: usize = crate::HISTORY_SINCE_MAX + crate::mailbox::MAILBOX_CAP + 64;
+
+impl QueueSettings {
+    pub fn from_env() -> QueueSettings {
+        let capacity = match env::var("NAMELESS_QUEUE") {
+            Ok(value) => queue_capacity(&value),
+            Err(_) => 1024,
+        };
+        let overflow = match env::var("NAMELESS_OVERFLOW") {
+            Ok(value) => Overflow::parse(&value).unwrap_or_else(|| {
+                eprintln!("Ignoring NAMELESS_OVERFLOW={}, using coalesce", value);
+                Overflow::Coalesce
+            }),
+            Err(_) => Overflow::Coalesce,
+        };
+        QueueSettings { capacity, overflow }
+    }
+}
+
+fn queue_capacity(value: &str) -> usize {
+    match value.parse::<usize>() {
+        Ok(capacity) if capacity >= MIN_QUEUE => capacity,
+        Ok(_) => {
+            eprintln!("NAMELESS_QUEUE={} can't hold a catch-up on join, using {}", value, MIN_QUEUE);
+            MIN_QUEUE
+        }
+        Err(_) => {
+            eprintln!("Ignoring NAMELESS_QUEUE={}, using 1024", value);
+            1024
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Pushed {
+    Queued,
+    Coalesced, // replaced an older frame with the same key
+    Dropped,
+    Overflow, // the queue is cut off, the client has to go
+    Closed,
+}
+
+struct Queue {
+    frames: VecDeque<(Option<String>, Packet)>,
+    closed: bool,
+}
+
+// Frames waiting for one client's writer. Pushing never waits, so a client that stops
+// reading only ever holds up itself.
+// Frames that only carry state (someone's typing, someone's presence) have a key, a newer
+// frame with the same key makes the older one pointless.
+pub struct Outbox {
+    queue: Mutex<Queue>,
+    settings: QueueSettings,
+    ready: Notify,   // something to write, or closed
+    drained: Notify, // the writer took a frame
+    cut_off: Notify, // give up on whatever is being written
+}
+
+impl Outbox {
+    pub fn new(settings: QueueSettings) -> Outbox {
+        Outbox {
+            queue: Mutex::new(Queue { frames: VecDeque::new(), closed: false }),
+            settings,
+            ready: Notify::new(),
+            drained: Notify::new(),
+            cut_off: Notify::new(),
+        }
+    }
+
+    pub fn push(&self, packet: Packet, key: Option<&str>) -> Pushed {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return Pushed::Closed;
+        }
+        if queue.frames.len() < self.settings.capacity {
+            queue.frames.push_back((key.map(str::to_string), packet));
+            drop(queue);
+            self.ready.notify_one();
+            return Pushed::Queued;
+        }
+
+        match (self.settings.overflow, key) {
+            (Overflow::Drop, _) => Pushed::Dropped,
+            (Overflow::Coalesce, Some(key)) => match queue.frames.iter().position(|(k, _)| k.as_deref() == Some(key)) {
+                // The newer state goes to the back, behind everything that happened before it
+                Some(stale) => {
+                    queue.frames.remove(stale);
+                    queue.frames.push_back((Some(key.to_string()), packet));
+                    Pushed::Coalesced
+                }
+                None => Pushed::Dropped,
+            },
+            _ => {
+                queue.closed = true;
+                queue.frames.clear();
+                drop(queue);
+                self.ready.notify_one();
+                self.cut_off.notify_one();
+                Pushed::Overflow
+            }
+        }
+    }
+
+    // Nothing more is taken, what's queued still goes out
+    pub fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.ready.notify_one();
+        self.drained.notify_waiters();
+    }
+
+    // None once it's closed and empty
+    async fn next(&self) -> Option<Packet> {
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some((_, packet)) = queue.frames.pop_front() {
+                    drop(queue);
+                    self.drained.notify_waiters();
+                    return Some(packet);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    // Waits until at most half the queue is taken, or it's closed. For bulk senders that
+    // should slow down to the reader's pace instead of tripping the overflow policy.
+    pub async fn room(&self) {
+        loop {
+            let mut drained = pin!(self.drained.notified());
+            drained.as_mut().enable();
+            {
+                let queue = self.queue.lock().unwrap();
+                if queue.closed || queue.frames.len() <= self.settings.capacity / 2 {
+                    return;
+                }
+            }
+            drained.await;
+        }
+    }
+}
+
+// Writes queued frames in order until the outbox is closed and empty. A write that takes
+// longer than `timeout` or gets cut off by an overflow is an error.
+pub async fn write_queued(writer: &mut (impl AsyncWrite + Unpin), outbox: &Outbox, timeout: Duration) -> io::Result<()> {
+    while let Some(packet) = outbox.next().await {
+        tokio::select! {
+            written = time::timeout(timeout, writer.write_all(&packet)) => match written {
+                Ok(result) => result?,
+                Err(_) => return Err(io::Error::new(ErrorKind::TimedOut, "stopped reading")),
+            },
+            _ = outbox.cut_off.notified() => return Err(io::Error::other("fell too far behind")),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    fn settings(capacity: usize, overflow: Overflow) -> QueueSettings {
+        QueueSettings { capacity, overflow }
+    }
+
+    fn packet(text: &str) -> Packet {
+        Arc::from(text.as_bytes())
+    }
+
+    fn queued(outbox: &Outbox) -> usize {
+        outbox.queue.lock().unwrap().frames.len()
+    }
+
+    // Server side of a local connection whose other end never reads
+    async fn stalled_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (server, client)
+    }
+
+    // Pushes big frames at a writer nobody reads from until the policy kicks in.
+    // The socket buffers take a few MB first.
+    async fn flood(outbox: &Outbox, key: Option<&str>) -> Pushed {
+        let big: Packet = Arc::from(vec![0u8; 60 * 1024]);
+        for _ in 0..2000 {
+            match outbox.push(Arc::clone(&big), key) {
+                Pushed::Queued => time::sleep(Duration::from_millis(1)).await,
+                other => return other,
+            }
+        }
+        panic!("the stalled reader never filled its queue");
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let frames = |outbox: &Outbox| outbox.queue.lock().unwrap().frames.iter().map(|(_, p)| p.clone()).collect::<Vec<_>>();
+
+        let outbox = Outbox::new(settings(2, Overflow::Drop));
+        assert_eq!(outbox.push(packet("a"), None), Pushed::Queued);
+        assert_eq!(outbox.push(packet("b"), None), Pushed::Queued);
+        assert_eq!(outbox.push(packet("c"), None), Pushed::Dropped);
+        assert_eq!(frames(&outbox), vec![packet("a"), packet("b")]);
+
+        let outbox = Outbox::new(settings(2, Overflow::Disconnect));
+        outbox.push(packet("a"), None);
+        outbox.push(packet("b"), Some("typing bob"));
+        assert_eq!(outbox.push(packet("c"), Some("typing bob")), Pushed::Overflow);
+        assert_eq!(queued(&outbox), 0);
+        assert_eq!(outbox.push(packet("d"), None), Pushed::Closed);
+
+        let outbox = Outbox::new(settings(3, Overflow::Coalesce));
+        outbox.push(packet("bob typing"), Some("typing bob"));
+        outbox.push(packet("msg"), None);
+        outbox.push(packet("ann online"), Some("presence ann"));
+        assert_eq!(outbox.push(packet("bob stopped"), Some("typing bob")), Pushed::Coalesced);
+        assert_eq!(frames(&outbox), vec![packet("msg"), packet("ann online"), packet("bob stopped")]);
+        assert_eq!(outbox.push(packet("cat typing"), Some("typing cat")), Pushed::Dropped);
+        assert_eq!(outbox.push(packet("msg 2"), None), Pushed::Overflow);
+    }
+
+    #[test]
+    fn queues_hold_at_least_a_catch_up() {
+        assert_eq!(queue_capacity("5000"), 5000);
+        assert_eq!(queue_capacity("1"), MIN_QUEUE);
+        assert_eq!(queue_capacity("lots"), 1024);
+    }
+
+    #[tokio::test]
+    async fn closed_outbox_still_flushes() {
+        let (mut server, mut client) = stalled_pair().await;
+        let outbox = Outbox::new(settings(8, Overflow::Disconnect));
+        outbox.push(packet("bye"), None);
+        outbox.close();
+        assert_eq!(outbox.push(packet("late"), None), Pushed::Closed);
+        write_queued(&mut server, &outbox, Duration::from_secs(5)).await.unwrap();
+        drop(server);
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"bye");
+    }
+
+    #[tokio::test]
+    async fn stalled_reader_is_cut_off_without_holding_up_others() {
+        let (mut stalled, _never_read) = stalled_pair().await;
+        let (mut fast, mut reader) = stalled_pair().await;
+        let slow_box = Arc::new(Outbox::new(settings(16, Overflow::Disconnect)));
+        let fast_box = Arc::new(Outbox::new(settings(128, Overflow::Disconnect)));
+        let slow_writer = tokio::spawn({
+            let slow_box = Arc::clone(&slow_box);
+            async move { write_queued(&mut stalled, &slow_box, Duration::from_secs(30)).await }
+        });
+        let fast_writer = tokio::spawn({
+            let fast_box = Arc::clone(&fast_box);
+            async move { write_queued(&mut fast, &fast_box, Duration::from_secs(30)).await }
+        });
+
+        // The stalled client gets the same traffic as the fast one, it just stops taking it
+        assert_eq!(flood(&slow_box, None).await, Pushed::Overflow);
+        for i in 0..100 {
+            assert_eq!(fast_box.push(packet(&format!("{:03}", i)), None), Pushed::Queued);
+        }
+        fast_box.close();
+
+        // Cut off straight away, not after the write timeout
+        let cut = time::timeout(Duration::from_secs(5), slow_writer).await.expect("writer still stuck").unwrap();
+        assert_eq!(cut.unwrap_err().to_string(), "fell too far behind");
+
+        fast_writer.await.unwrap().unwrap();
+        let mut received = String::new();
+        reader.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, (0..100).map(|i| format!("{:03}", i)).collect::<String>());
+    }
+
+    #[tokio::test]
+    async fn stalled_reader_under_drop_keeps_its_connection() {
+        let (mut stalled, _never_read) = stalled_pair().await;
+        let outbox = Arc::new(Outbox::new(settings(16, Overflow::Drop)));
+        let writer = tokio::spawn({
+            let outbox = Arc::clone(&outbox);
+            async move { write_queued(&mut stalled, &outbox, Duration::from_secs(30)).await }
+        });
+        assert_eq!(flood(&outbox, None).await, Pushed::Dropped);
+        assert_eq!(queued(&outbox), 16);
+        assert!(!writer.is_finished());
+        writer.abort();
+    }
+
+    #[tokio::test]
+    async fn stalled_reader_trips_the_write_timeout() {
+        let (mut stalled, _never_read) = stalled_pair().await;
+        let outbox = Arc::new(Outbox::new(settings(4, Overflow::Drop)));
+        let writer = tokio::spawn({
+            let outbox = Arc::clone(&outbox);
+            async move { write_queued(&mut stalled, &outbox, Duration::from_millis(200)).await }
+        });
+        flood(&outbox, None).await;
+        let result = time::timeout(Duration::from_secs(5), writer).await.expect("writer never timed out").unwrap();
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn bulk_sender_waits_for_room() {
+        let (mut server, mut client) = stalled_pair().await;
+        let outbox = Arc::new(Outbox::new(settings(4, Overflow::Disconnect)));
+        for text in ["a", "b", "c", "d"] {
+            outbox.push(packet(text), None);
+        }
+        assert!(time::timeout(Duration::from_millis(50), outbox.room()).await.is_err());
+
+        let writer = tokio::spawn({
+            let outbox = Arc::clone(&outbox);
+            async move { write_queued(&mut server, &outbox, Duration::from_secs(5)).await }
+        });
+        time::timeout(Duration::from_secs(5), outbox.room()).await.expect("the queue never drained");
+        assert!(queued(&outbox) <= 2);
+        outbox.close();
+        writer.await.unwrap().unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"abcd");
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    env,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+// Typing updates are throttled on their own and don't count at all
+pub const EXEMPT_COMMANDS: &[&str] = &["typing"];
+// File data comes as fast as the disk reads it, so it gets its own bigger bucket and is
+// slowed down instead of dropped. Read receipts count as messages like everything else.
+pub const BULK_COMMANDS: &[&str] = &["chunk", "done"];
+
+const WARNINGS: u32 = 3; // strikes that only get a warning
+const MUTE_STRIKE: u32 = WARNINGS + 1; // this one gets a temporary mute
+const DISCONNECT_STRIKE: u32 = MUTE_STRIKE + 2; // and still going means goodbye
+const STRIKE_GAP: Duration = Duration::from_secs(1); // one strike per second at most, the rest are just dropped
+const STRIKE_MEMORY: Duration = Duration::from_secs(60); // a quiet minute wipes the slate
+pub const FLOOD_MUTE_SECS: u64 = 30;
+
+const TYPING_INTERVAL: Duration = Duration::from_secs(2); // a repeated typing state is relayed at most this often
+const TYPING_GAP: Duration = Duration::from_millis(500); // and any typing update at most this often
+
+pub static LIMITS: LazyLock<RateLimits> = LazyLock::new(RateLimits::from_env);
+
+// Limits for one connection, set with environment variables:
+//   NAMELESS_MSG_RATE / NAMELESS_MSG_BURST    frames per second / frames at once
+//   NAMELESS_BYTE_RATE / NAMELESS_BYTE_BURST  plaintext bytes per second / at once
+//   NAMELESS_BULK_RATE / NAMELESS_BULK_BURST  the same in bytes for file data
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    pub messages_per_sec: f64,
+    pub message_burst: f64,
+    pub bytes_per_sec: f64,
+    pub byte_burst: f64,
+    pub bulk_per_sec: f64,
+    pub bulk_burst: f64,
+}
+
+impl RateLimits {
+    pub fn from_env() -> RateLimits {
+        let read = |name: &str, default: f64| match env::var(name) {
+            Ok(value) => value.parse().ok().filter(|v: &f64| *v > 0.0).unwrap_or_else(|| {
+                eprintln!("Ignoring {}={}, using {}", name, value, default);
+                default
+            }),
+            Err(_) => default,
+        };
+        RateLimits {
+            messages_per_sec: read("NAMELESS_MSG_RATE", 5.0),
+            message_burst: read("NAMELESS_MSG_BURST", 10.0),
+            bytes_per_sec: read("NAMELESS_BYTE_RATE", 16.0 * 1024.0),
+            byte_burst: read("NAMELESS_BYTE_BURST", 64.0 * 1024.0),
+            bulk_per_sec: read("NAMELESS_BULK_RATE", 1024.0 * 1024.0),
+            bulk_burst: read("NAMELESS_BULK_BURST", 4.0 * 1024.0 * 1024.0),
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    refill_per_sec: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(refill_per_sec: f64, capacity: f64) -> Self {
+        TokenBucket { tokens: capacity, capacity, refill_per_sec, last: Instant::now() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last = now;
+    }
+
+    // Takes `amount` even when it isn't there yet, and says how long until it would have been
+    fn borrow(&mut self, now: Instant, amount: f64) -> Duration {
+        self.refill(now);
+        self.tokens -= amount.min(self.capacity);
+        if self.tokens >= 0.0 { Duration::ZERO } else { Duration::from_secs_f64(-self.tokens / self.refill_per_sec) }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Wait(Duration), // bulk frames, relayed after the pause
+    Drop, // over the limit, already told
+    Warn,
+    Mute,
+    Disconnect,
+}
+
+// Token buckets for one connection plus how often it has gone over them
+pub struct RateLimiter {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    bulk: TokenBucket,
+    strikes: u32,
+    last_strike: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter::with_limits(*LIMITS)
+    }
+
+    fn with_limits(limits: RateLimits) -> Self {
+        RateLimiter {
+            messages: TokenBucket::new(limits.messages_per_sec, limits.message_burst),
+            bytes: TokenBucket::new(limits.bytes_per_sec, limits.byte_burst),
+            bulk: TokenBucket::new(limits.bulk_per_sec, limits.bulk_burst),
+            strikes: 0,
+            last_strike: None,
+        }
+    }
+
+    // Charges one frame of `size` bytes. Frames over the limit are never relayed,
+    // the verdict says what else should happen to the sender.
+    pub fn check(&mut self, cmd: &str, size: usize) -> Verdict {
+        self.check_at(cmd, size, Instant::now())
+    }
+
+    fn check_at(&mut self, cmd: &str, size: usize, now: Instant) -> Verdict {
+        if EXEMPT_COMMANDS.contains(&cmd) {
+            return Verdict::Allow;
+        }
+        if BULK_COMMANDS.contains(&cmd) {
+            return match self.bulk.borrow(now, size as f64) {
+                Duration::ZERO => Verdict::Allow,
+                wait => Verdict::Wait(wait),
+            };
+        }
+        self.messages.refill(now);
+        self.bytes.refill(now);
+        let size = size as f64;
+        if self.messages.tokens >= 1.0 && self.bytes.tokens >= size.min(self.bytes.capacity) {
+            self.messages.tokens -= 1.0;
+            self.bytes.tokens -= size;
+            return Verdict::Allow;
+        }
+
+        match self.last_strike {
+            Some(last) if now.duration_since(last) < STRIKE_GAP => return Verdict::Drop,
+            Some(last) if now.duration_since(last) > STRIKE_MEMORY => self.strikes = 0,
+            _ => {}
+        }
+        self.last_strike = Some(now);
+        self.strikes += 1;
+        match self.strikes {
+            n if n <= WARNINGS => Verdict::Warn,
+            MUTE_STRIKE => Verdict::Mute,
+            n if n >= DISCONNECT_STRIKE => Verdict::Disconnect,
+            _ => Verdict::Drop,
+        }
+    }
+}
+
+// One user's typing updates. The same state again within TYPING_INTERVAL is dropped, so
+// key-by-key updates stay cheap, and nothing is relayed within TYPING_GAP of the last one
+// so flipping between start and stop can't flood the room. A change that comes too soon
+// is held until the gap is over, the room always ends up with the latest state.
+pub struct TypingThrottle {
+    relayed: Option<(String, Instant)>,
+    held: Option<String>,
+}
+
+impl TypingThrottle {
+    pub fn new() -> Self {
+        TypingThrottle { relayed: None, held: None }
+    }
+
+    // The state to relay now, if any
+    pub fn update(&mut self, state: &str, now: Instant) -> Option<String> {
+        self.held = None;
+        match &self.relayed {
+            Some((last, at)) if last == state && now.duration_since(*at) < TYPING_INTERVAL => None,
+            Some((_, at)) if now.duration_since(*at) < TYPING_GAP => {
+                self.held = Some(state.to_string());
+                None
+            }
+            _ => Some(self.relay(state.to_string(), now)),
+        }
+    }
+
+    // When a held state is due, None when nothing is held
+    pub fn due(&self) -> Option<Instant> {
+        self.held.as_ref()?;
+        self.relayed.as_ref().map(|(_, at)| *at + TYPING_GAP)
+    }
+
+    // The held state once it's due
+    pub fn flush(&mut self, now: Instant) -> Option<String> {
+        if self.due()? > now {
+            return None;
+        }
+        let state = self.held.take()?;
+        Some(self.relay(state, now))
+    }
+
+    fn relay(&mut self, state: String, now: Instant) -> String {
+        self.relayed = Some((state.clone(), now));
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Barely refills, so only the strikes move
+    const SLOW: RateLimits = RateLimits { messages_per_sec: 0.001, message_burst: 2.0, bytes_per_sec: 1000.0, byte_burst: 1000.0, bulk_per_sec: 1000.0, bulk_burst: 1000.0 };
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limits = RateLimits { messages_per_sec: 2.0, ..SLOW };
+        let mut limiter = RateLimiter::with_limits(limits);
+        let start = Instant::now();
+        assert_eq!(limiter.check_at("say", 10, start), Verdict::Allow);
+        assert_eq!(limiter.check_at("say", 10, start), Verdict::Allow);
+        assert_eq!(limiter.check_at("say", 10, start), Verdict::Warn);
+        assert_eq!(limiter.check_at("say", 10, start + Duration::from_millis(600)), Verdict::Allow);
+        // Bytes run out on their own
+        assert_eq!(limiter.check_at("say", 900, start + Duration::from_secs(5)), Verdict::Allow);
+        assert_eq!(limiter.check_at("say", 900, start + Duration::from_secs(5)), Verdict::Warn);
+    }
+
+    #[test]
+    fn flooding_warns_then_mutes_then_disconnects() {
+        let mut limiter = RateLimiter::with_limits(SLOW);
+        let start = Instant::now();
+        let at = |secs: f64| start + Duration::from_secs_f64(secs);
+        assert_eq!(limiter.check_at("say", 1, at(0.0)), Verdict::Allow);
+        assert_eq!(limiter.check_at("say", 1, at(0.0)), Verdict::Allow);
+        let verdicts: Vec<Verdict> = (0..7).map(|n| limiter.check_at("say", 1, at(n as f64 * 1.1))).collect();
+        assert_eq!(verdicts, vec![Verdict::Warn, Verdict::Warn, Verdict::Warn, Verdict::Mute, Verdict::Drop, Verdict::Disconnect, Verdict::Disconnect]);
+        // Within a second of the last strike it's only dropped
+        assert_eq!(limiter.check_at("say", 1, at(6.7)), Verdict::Drop);
+        // and a quiet minute starts over
+        assert_eq!(limiter.check_at("say", 1, at(80.0)), Verdict::Warn);
+    }
+
+    #[test]
+    fn bulk_frames_wait_and_typing_is_free() {
+        let mut limiter = RateLimiter::with_limits(SLOW);
+        let start = Instant::now();
+        assert_eq!(limiter.check_at("chunk", 600, start), Verdict::Allow);
+        assert_eq!(limiter.check_at("chunk", 600, start), Verdict::Wait(Duration::from_millis(200)));
+        assert_eq!(limiter.check_at("done", 10, start + Duration::from_millis(210)), Verdict::Allow);
+        for _ in 0..100 {
+            assert_eq!(limiter.check_at("typing", 20, start), Verdict::Allow);
+        }
+        // none of it touched the message bucket, read receipts do
+        assert_eq!(limiter.check_at("read", 10, start), Verdict::Allow);
+        assert_eq!(limiter.check_at("say", 1, start), Verdict::Allow);
+        assert_eq!(limiter.check_at("read", 10, start), Verdict::Warn);
+    }
+
+    #[test]
+    fn typing_is_throttled_whatever_the_state() {
+        let mut typing = TypingThrottle::new();
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        assert_eq!(typing.update("start", at(0)).as_deref(), Some("start"));
+        // Flipping back and forth is held back, ending where it started sends nothing
+        for n in 1..=10 {
+            let state = if n % 2 == 0 { "start" } else { "stop" };
+            assert_eq!(typing.update(state, at(n * 10)), None);
+        }
+        assert_eq!(typing.due(), None);
+        // A change too soon goes out once the gap is over
+        assert_eq!(typing.update("stop", at(200)), None);
+        assert_eq!(typing.due(), Some(at(500)));
+        assert_eq!(typing.flush(at(400)), None);
+        assert_eq!(typing.flush(at(500)).as_deref(), Some("stop"));
+        assert_eq!(typing.flush(at(600)), None);
+        // unless it's undone first
+        assert_eq!(typing.update("start", at(600)), None);
+        assert_eq!(typing.update("stop", at(700)), None);
+        assert_eq!(typing.due(), None);
+        assert_eq!(typing.update("stop", at(2600)).as_deref(), Some("stop"));
+    }
+}
@@ -1,18 +1,116 @@
+mod auth;
+mod disk;
+mod history;
+mod lobby;
+mod mailbox;
+mod moderation;
+mod motd;
+mod outbox;
+mod ratelimit;
+mod transfers;
+mod users;
+
 use std::{
     collections::HashMap,
-    io::{self, BufRead, BufReader, Write},
-    net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    io::{self, Write},
+    net::{IpAddr, UdpSocket},
+    path::Path,
     sync::{Arc, Mutex},
-    thread,
+    time::{Duration, Instant},
+};
+use aes_gcm::Aes256Gcm;
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, tcp::OwnedWriteHalf},
+    sync::Notify,
+    time,
+};
+use auth::ServerKey;
+use disk::Disk;
+use nameless_proto::{
+    auth::{Intro, UserKey},
+    frame::{self, Line},
+    lobby::SERVER_PORT,
 };
-use aes_gcm::{Key, Aes256Gcm};
-use rand::Rng;
-use std::io::Read;
+use history::{History, Record, SearchQuery, SharedHistory};
+use lobby::Lobby;
+use mailbox::{Mailbox, SharedMailbox};
+use moderation::{Ban, BanTarget, Moderation, Role, SharedModeration};
+use motd::{Motd, SharedMotd};
+use outbox::{Outbox, Packet, Pushed};
+use ratelimit::{RateLimiter, TypingThrottle, Verdict};
+use transfers::{Offer, SharedTransfers, Transfers};
+use users::{SharedUsers, Users};
+
+type ClientList = Arc<Mutex<HashMap<String, Client>>>; // username -> connection
+
+const DEFAULT_ROOM: &str = "general";
+const HISTORY_FILE: &str = "history.log";
+const HISTORY_REPLAY: usize = 50; // messages replayed when entering a room
+const HISTORY_SINCE_MAX: usize = 500; // cap when a client asks for everything since an id
+const SEARCH_PAGE: usize = 20; // results per page unless the client asks for fewer
+const SEARCH_PAGE_MAX: usize = 50;
+const SEARCH_EXCERPT_CHARS: usize = 80;
+const SEARCH_SCAN: usize = 10_000; // messages looked at per page, /more picks up from there
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30); // to send the intro and answer the challenge
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30); // a client that takes no data for this long is dropped, and a file chunk waits this long for room
+const USERS_FILE: &str = "users.txt";
+const MAILBOX_FILE: &str = "mailbox.log";
+const ROLES_FILE: &str = "roles.txt";
+const BANS_FILE: &str = "bans.txt";
+const MOTD_FILE: &str = "motd.txt";
+const INVITE_EXPIRY_SECS: u64 = 24 * 60 * 60; // unless the inviter picks another
+const LOBBY_TIMEOUT: Duration = Duration::from_secs(10); // for the lobby to answer an invite
+// What a muted user can't do, typing updates from them are dropped without an error
+const TALKING_COMMANDS: &[&str] = &["say", "me", "dm", "edit", "react", "unreact", "offer", "nick", "typing", "invite"];
 
+struct Client {
+    connection: Connection,
+    room: String, // messages only go to clients in the same room
+    ip: IpAddr,   // for bans by address
+}
+
+// One client's connection as the rest of the server sees it. Frames are queued for its
+// writer task instead of written in place, so nobody ever waits on someone else's socket.
+#[derive(Clone)]
+struct Connection {
+    outbox: Arc<Outbox>,
+    closed: Arc<Notify>, // wakes the reader so the session ends
+}
+
+impl Connection {
+    // Starts the writer task that owns the socket's write half
+    fn start(writer: OwnedWriteHalf) -> Connection {
+        let outbox = Arc::new(Outbox::new(*outbox::QUEUES));
+        let closed = Arc::new(Notify::new());
+        tokio::spawn(frame_writer(writer, Arc::clone(&outbox), Arc::clone(&closed)));
+        Connection { outbox, closed }
+    }
 
-type SharedStream = Arc<TcpStream>;
-type ClientList = Arc<Mutex<HashMap<String, SharedStream>>>; // username -> stream
-type AESKey = [u8; 32];
+    fn send(&self, packet: Packet) -> bool {
+        self.outbox.push(packet, None) == Pushed::Queued
+    }
+
+    // Ends the session, whatever is already queued still goes out
+    fn close(&self) {
+        self.outbox.close();
+        self.closed.notify_one();
+    }
+
+    fn is(&self, other: &Connection) -> bool {
+        Arc::ptr_eq(&self.outbox, &other.outbox)
+    }
+}
+
+async fn frame_writer(mut writer: OwnedWriteHalf, outbox: Arc<Outbox>, closed: Arc<Notify>) {
+    let peer = writer.peer_addr().map_or("a client".to_string(), |addr| addr.to_string());
+    if let Err(e) = outbox::write_queued(&mut writer, &outbox, WRITE_TIMEOUT).await {
+        eprintln!("Dropping {}: {}", peer, e);
+    }
+    outbox.close();
+    closed.notify_one();
+    writer.shutdown().await.ok();
+}
 
 fn get_ip() -> String {
     let socket = UdpSocket::bind("0.0.0.0:0").expect("Error binding to socket for IP detection");
@@ -20,14 +118,8 @@ fn get_ip() -> String {
     socket.local_addr().unwrap().ip().to_string()
 }
 
-fn generate_aes_key() -> AESKey {
-    let mut rng = rand::thread_rng();
-    let mut key = [0u8; 32];
-    rng.fill(&mut key);
-    key
-}
-
-fn msg_fetcher(stream: TcpStream, clients: ClientList, aes_key: Arc<AESKey>) {
+#[allow(clippy::too_many_arguments)]
+async fn msg_fetcher(stream: TcpStream, clients: ClientList, history: SharedHistory, users: SharedUsers, mailbox: SharedMailbox, transfers: SharedTransfers, moderation: SharedModeration, motd: SharedMotd, lobby: Arc<Lobby>, server_key: Arc<ServerKey>) {
     let peer = match stream.peer_addr() {
         Ok(addr) => addr,
         Err(_) => {
@@ -36,155 +128,1089 @@ fn msg_fetcher(stream: TcpStream, clients: ClientList, aes_key: Arc<AESKey>) {
         }
     };
 
-    let stream = Arc::new(stream);
+    // One reader for the intro, the handshake and the frames, nothing it buffered gets lost
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
 
-    let intro_stream = match TcpStream::try_clone(&stream) {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Failed to clone stream for intro reading: {}", e);
+    let greeting = time::timeout(HANDSHAKE_TIMEOUT, async {
+        let line = auth::read_line(&mut reader).await?;
+        // An unreadable intro gets as far as a refusal, see below
+        let intro = Intro::parse(&line).unwrap_or_else(|| Intro { name: String::new(), since: None });
+        if !auth::handshake(&mut write_half, &mut reader, &server_key).await? {
+            return Ok((intro, None));
+        }
+        let user_key = auth::identify(&mut write_half, &mut reader, &server_key).await?;
+        Ok::<_, io::Error>((intro, Some(user_key)))
+    });
+    let (Intro { name: mut username, since }, user_key) = match greeting.await {
+        Ok(Ok((intro, Some(user_key)))) => (intro, user_key),
+        Ok(Ok((intro, None))) => {
+            println!("Refused {} from {}: wrong password", intro.name, peer.ip());
+            write_half.shutdown().await.ok();
             return;
         }
-    };
-
-    let reader_stream = match TcpStream::try_clone(&stream) {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Failed to clone stream: {}", e);
+        Ok(Err(e)) => {
+            eprintln!("Handshake with {} failed: {}", peer, e);
+            return;
+        }
+        Err(_) => {
+            eprintln!("Handshake with {} timed out", peer);
             return;
         }
     };
-    
-    let mut reader = BufReader::new(reader_stream);
-    let mut intro_reader = BufReader::new(intro_stream);
-    let mut intro = String::new();
-    if intro_reader.read_line(&mut intro).is_err() {
-        eprintln!("Failed to read intro message from client");
-        return;
-    }
-    let username = parse_username(&intro).unwrap_or_else(|| peer.to_string());
 
-    if let Err(e) = add_client_to_list(&clients, username.clone(), Arc::clone(&stream)) {
-        eprintln!("Error adding client: {}", e);
+    let cipher = nameless_proto::auth::cipher(&server_key.key);
+    let connection = Connection::start(write_half);
+
+    // Refused clients still finish the handshake so they can read why, then the connection closes
+    let refusal = match user_key {
+        _ if !is_valid_name(&username) => Some("that is not a valid name".to_string()),
+        None => Some("your identity didn't check out".to_string()),
+        Some(user_key) => users.lock().unwrap().claim(&username, &user_key).err(),
+    };
+    let refusal = refusal.or_else(|| moderation.lock().unwrap().ban_for(&username, peer.ip()).map(ban_notice));
+    if let Some(refusal) = refusal {
+        println!("Refused client {} from {}: {}", username, peer.ip(), refusal);
+        refuse(&connection, &cipher, refusal);
         return;
     }
-    // stream.write_all(aes_key.as_ref())
-    
-    let mut raw_stream = match TcpStream::try_clone(&stream) {
-        Ok(s) => s,
+    let Some(user_key) = user_key else {
+        return;
+    };
+
+    // Only join the list once the key is out, so no frame can overtake it
+    match add_client_to_list(&clients, &cipher, username.clone(), connection.clone(), peer.ip()) {
+        Ok(true) => {}
+        Ok(false) => {
+            println!("Refused client {} from {}: already online", username, peer.ip());
+            refuse(&connection, &cipher, format!("{} is already online", username));
+            return;
+        }
         Err(e) => {
-            eprintln!("Failed to clone stream for sending AES key: {}", e);
+            eprintln!("Error adding client: {}", e);
             return;
         }
-    };
-    
-    if let Err(e) = raw_stream.write_all(aes_key.as_ref()) {
-        eprintln!("Failed to send AES key to {}: {}", username, e);
-        return;
     }
-    
-    // let mut reader = BufReader::new(reader_stream);
-    // let mut buffer = String::new();
+    if let Err(e) = send_motd(&clients, &motd, &cipher, &username) {
+        eprintln!("Failed to send the MOTD to {}: {}", username, e);
+    }
+    let role = moderation.lock().unwrap().role(&username);
+    if role != Role::Member {
+        send_line(&clients, &cipher, &username, &Line::new("system").arg(format!("you are the {} here", role))).ok();
+    }
+    announce_presence(&clients, &cipher, &username, "online");
+    let replayed = replay_history(&clients, &history, &cipher, &username, DEFAULT_ROOM, since).unwrap_or_else(|e| {
+        eprintln!("Failed to replay history to {}: {}", username, e);
+        Vec::new()
+    });
+    if let Err(e) = deliver_mailbox(&clients, &mailbox, &cipher, &username, &replayed) {
+        eprintln!("Failed to deliver queued messages to {}: {}", username, e);
+    }
+
+    let mut typing = TypingThrottle::new();
+    let mut limiter = RateLimiter::new();
 
     loop {
-        // buffer.clear();
-        let mut nonce_bytes = [0u8; 12];
-        if reader.read_exact(&mut nonce_bytes).is_err() {
-            break;
+        let read = tokio::select! {
+            read = frame::read_frame_async(&mut reader, &cipher) => read,
+            _ = connection.closed.notified() => break,
+            _ = time::sleep_until(typing.due().unwrap_or_else(Instant::now).into()), if typing.due().is_some() => {
+                if let Some(state) = typing.flush(Instant::now()) {
+                    relay_typing(&clients, &cipher, &username, &state).ok();
+                }
+                continue;
+            }
+        };
+        let plaintext = match read {
+            Ok(p) => p,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                eprintln!("Dropping bad frame from {}: {}", username, e);
+                continue;
+            }
+            Err(_) => break,
+        };
+        let Some(line) = Line::parse(&plaintext) else {
+            continue;
+        };
+
+        // Frames over the rate limit are dropped, repeat offenders are muted and then thrown out.
+        // Bulk frames just wait, which slows the sender down through TCP.
+        match limiter.check(&line.cmd, plaintext.len()) {
+            Verdict::Allow => {}
+            Verdict::Wait(pause) => time::sleep(pause).await,
+            Verdict::Drop => continue,
+            Verdict::Warn => {
+                send_error(&clients, &cipher, &username, "slow down, you are sending too fast").ok();
+                continue;
+            }
+            Verdict::Mute => {
+                let mut moderation_lock = moderation.lock().unwrap();
+                if !moderation_lock.is_muted(&username) {
+                    moderation_lock.mute(&username, Some(history::now().saturating_add(ratelimit::FLOOD_MUTE_SECS)));
+                }
+                drop(moderation_lock);
+                println!("Muted {} for flooding", username);
+                let notice = format!("you are muted {} for flooding", describe_duration(Some(ratelimit::FLOOD_MUTE_SECS)));
+                send_error(&clients, &cipher, &username, &notice).ok();
+                continue;
+            }
+            Verdict::Disconnect => {
+                println!("Disconnecting {} for flooding", username);
+                send_error(&clients, &cipher, &username, "disconnected for flooding").ok();
+                break;
+            }
         }
-        let mut size_buf = [0u8; 2];
-        if reader.read_exact(&mut size_buf).is_err() {
-            break;
+
+        // Bans and mutes are checked again on every attempt to talk
+        if TALKING_COMMANDS.contains(&line.cmd.as_str()) {
+            let mut moderation_lock = moderation.lock().unwrap();
+            if let Some(notice) = moderation_lock.ban_for(&username, peer.ip()).map(ban_notice) {
+                drop(moderation_lock);
+                send_error(&clients, &cipher, &username, &notice).ok();
+                break;
+            }
+            let muted = moderation_lock.is_muted(&username);
+            drop(moderation_lock);
+            if muted {
+                if line.cmd != "typing" {
+                    send_error(&clients, &cipher, &username, "you are muted").ok();
+                }
+                continue;
+            }
         }
-        let size = u16::from_be_bytes(size_buf) as usize;
-        let mut ciphertext = vec![0u8; size];
-        if reader.read_exact(&mut ciphertext).is_err() {
+
+        let result = match line.cmd.as_str() {
+            "say" | "me" => match line.get_tag("in_reply_to").map(str::parse).transpose() {
+                Ok(in_reply_to) => {
+                    let kind = if line.cmd == "say" { "msg" } else { "action" };
+                    let text = line.get(0).unwrap_or_default();
+                    relay_to_room(&clients, &history, &users, &mailbox, &cipher, &username, kind, text, in_reply_to)
+                        .and_then(|id| acknowledge(&clients, &cipher, &username, &line, id))
+                }
+                Err(_) => send_error(&clients, &cipher, &username, "in_reply_to must be a message id"),
+            },
+            "search" => search_history(&clients, &history, &cipher, &username, &line),
+            "thread" => match line.get(0).and_then(|id| id.parse().ok()) {
+                Some(id) => send_thread(&clients, &history, &cipher, &username, id),
+                None => send_error(&clients, &cipher, &username, "usage: thread <id>"),
+            },
+            "edit" => match (line.get(0).and_then(|id| id.parse().ok()), line.get(1)) {
+                (Some(id), Some(text)) => modify_message(&clients, &history, &moderation, &cipher, &username, id, Some(text)),
+                _ => send_error(&clients, &cipher, &username, "usage: edit <id> :<text>"),
+            },
+            "delete" => match line.get(0).and_then(|id| id.parse().ok()) {
+                Some(id) => modify_message(&clients, &history, &moderation, &cipher, &username, id, None),
+                None => send_error(&clients, &cipher, &username, "usage: delete <id>"),
+            },
+            "react" | "unreact" => match (line.get(0).and_then(|id| id.parse().ok()), line.get(1)) {
+                (Some(id), Some(emoji)) if is_valid_emoji(emoji) => {
+                    update_reaction(&clients, &history, &cipher, &username, id, emoji, line.cmd == "react")
+                }
+                _ => send_error(&clients, &cipher, &username, &format!("usage: {} <id> <emoji>", line.cmd)),
+            },
+            "read" => match line.get(0).and_then(|id| id.parse().ok()) {
+                Some(id) => forward_read_receipt(&clients, &history, &cipher, &username, id),
+                None => send_error(&clients, &cipher, &username, "usage: read <id>"),
+            },
+            "join" => match line.get(0) {
+                Some(room) if is_valid_name(room) => switch_room(&clients, &history, &cipher, &username, room),
+                _ => send_error(&clients, &cipher, &username, "usage: join <room>"),
+            },
+            "leave" => switch_room(&clients, &history, &cipher, &username, DEFAULT_ROOM),
+            "nick" => match line.get(0) {
+                Some(new_name) if is_valid_name(new_name) => {
+                    change_nick(&clients, &users, &transfers, &moderation, &cipher, &user_key, &mut username, new_name)
+                }
+                _ => send_error(&clients, &cipher, &username, "usage: nick <name>"),
+            },
+            "typing" => match line.get(0) {
+                Some(state @ ("start" | "stop")) => match typing.update(state, Instant::now()) {
+                    Some(state) => relay_typing(&clients, &cipher, &username, &state),
+                    None => Ok(()),
+                },
+                _ => send_error(&clients, &cipher, &username, "usage: typing start|stop"),
+            },
+            "motd" => match line.get(0) {
+                Some(text) => set_motd(&clients, &moderation, &motd, &cipher, &username, text),
+                None => send_motd(&clients, &motd, &cipher, &username),
+            },
+            "invite" => create_invite(&clients, &lobby, &cipher, &username, &line).await,
+            "who" => send_line(&clients, &cipher, &username, &roster(&clients)).map(|_| ()),
+            "dm" => match (line.get(0), line.get(1)) {
+                (Some(recipient), Some(text)) => {
+                    send_private_message(&clients, &history, &users, &mailbox, &cipher, &username, recipient, text)
+                        .and_then(|id| acknowledge(&clients, &cipher, &username, &line, id))
+                }
+                _ => send_error(&clients, &cipher, &username, "usage: dm <user> :<text>"),
+            },
+            "offer" => offer_file(&clients, &transfers, &cipher, &username, &line),
+            "accept" => match (line.get(0).and_then(|id| id.parse().ok()), line.get(1).and_then(|o| o.parse().ok())) {
+                (Some(id), Some(offset)) => accept_file(&clients, &transfers, &cipher, &username, id, offset),
+                _ => send_error(&clients, &cipher, &username, "usage: accept <transfer> <offset>"),
+            },
+            "reject" => match line.get(0).and_then(|id| id.parse().ok()) {
+                Some(id) => reject_file(&clients, &transfers, &cipher, &username, id),
+                None => send_error(&clients, &cipher, &username, "usage: reject <transfer>"),
+            },
+            "chunk" | "done" => relay_file_data(&clients, &transfers, &cipher, &username, &line).await,
+            "kick" => match line.get(0) {
+                Some(target) => kick_user(&clients, &moderation, &cipher, &username, target, line.get(1).unwrap_or_default()),
+                None => send_error(&clients, &cipher, &username, "usage: kick <user> :<reason>"),
+            },
+            "ban" => match (line.get(0), line.get(1).and_then(moderation::parse_duration)) {
+                (Some(target), Some(duration)) => {
+                    ban_target(&clients, &moderation, &cipher, &username, target, duration, line.get(2).unwrap_or_default())
+                }
+                _ => send_error(&clients, &cipher, &username, "usage: ban <user|ip> <30s|10m|2h|7d|perm> :<reason>"),
+            },
+            "unban" => match line.get(0) {
+                Some(target) => unban_target(&clients, &moderation, &cipher, &username, target),
+                None => send_error(&clients, &cipher, &username, "usage: unban <user|ip>"),
+            },
+            "mute" => match (line.get(0), line.get(1).and_then(moderation::parse_duration)) {
+                (Some(target), Some(duration)) => mute_user(&clients, &moderation, &cipher, &username, target, Some(duration)),
+                _ => send_error(&clients, &cipher, &username, "usage: mute <user> <30s|10m|2h|7d|perm>"),
+            },
+            "unmute" => match line.get(0) {
+                Some(target) => mute_user(&clients, &moderation, &cipher, &username, target, None),
+                None => send_error(&clients, &cipher, &username, "usage: unmute <user>"),
+            },
+            "op" | "deop" => match line.get(0) {
+                Some(target) if is_valid_name(target) => set_moderator(&clients, &users, &moderation, &cipher, &username, target, line.cmd == "op"),
+                _ => send_error(&clients, &cipher, &username, &format!("usage: {} <user>", line.cmd)),
+            },
+            other => send_error(&clients, &cipher, &username, &format!("unknown command '{}'", other)),
+        };
+        if let Err(e) = result {
+            eprintln!("Error handling frame from {}: {}", username, e);
             break;
         }
-        let mut message = Vec::with_capacity(12 + 2 + size);
-        message.extend_from_slice(&nonce_bytes);
-        message.extend_from_slice(&size_buf);
-        message.extend_from_slice(&ciphertext);
-        if let Err(e) = broadcast_message(&clients, &username, &message) {
-            eprintln!("Broadcast error: {}", e);
-            break;
+    }
+
+    connection.close();
+    if disconnect_client(&clients, &username, &connection) {
+        announce_presence(&clients, &cipher, &username, "offline");
+        let abandoned = transfers.lock().unwrap().remove_from(&username);
+        for (id, receivers) in abandoned {
+            for receiver in receivers {
+                send_line(&clients, &cipher, &receiver, &Line::new("cancel").arg(id.to_string())).ok();
+            }
         }
-        // match reader.read_line(&mut buffer) {
-        //     Ok(0) => break,
-        //     Ok(_) if buffer.trim().is_empty() => break,
-        //     Ok(_) => {
-        //         let message = format!("{}",buffer.trim());
-        //         print!("{}", message);
-        //         // println!("{}", message);
+    }
+}
+
+// "@name" tokens in a message, trailing punctuation ignored
+fn mentioned_names(text: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = text
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| name.trim_end_matches(|c: char| !c.is_alphanumeric() && c != '_' && c != '-'))
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// A reaction is one short word, and ':' ',' ';' are taken by the "reactions" tag format
+fn is_valid_emoji(emoji: &str) -> bool {
+    !emoji.is_empty() && emoji.len() <= 32 && !emoji.contains([':', ',', ';']) && !emoji.contains(char::is_whitespace) && !emoji.contains(char::is_control)
+}
+
+// Usernames and room names are single protocol words, ',' separates names in the "mentions" tag
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 32 && !name.starts_with(':') && !name.contains(',') && !name.contains(char::is_whitespace) && !name.contains(char::is_control)
+}
 
-        //         if let Err(e) = broadcast_message(&clients, &username, &message) {
-        //             eprintln!("Broadcast error: {}", e);
-        //             break;
-        //         }
-        //     }
-        //     Err(e) => {
-        //         eprintln!("Read error from {}: {}", peer, e);
-        //         break;
-        //     }
-        // }
+// The new client gets the current roster before anyone else can write to it.
+// Ok(false) when the name is already online, the session there stays.
+fn add_client_to_list(clients: &ClientList, cipher: &Aes256Gcm, username: String, connection: Connection, ip: IpAddr) -> io::Result<bool> {
+    let mut clients_lock = clients.lock().unwrap();
+    if clients_lock.contains_key(&username) {
+        return Ok(false);
     }
+    let roster = roster_of(clients_lock.keys().chain([&username]));
+    connection.send(frame::seal_frame(cipher, &roster.to_string())?.into());
 
-    // if let Err(e) = disconnect_client(&clients, &username, Arc::clone(&stream)) {
-    //     eprintln!("Error removing client: {}", e);
-    // }
+    clients_lock.insert(username, Client { connection, room: DEFAULT_ROOM.to_string(), ip });
+    Ok(true)
 }
 
-fn parse_username(intro: &str) -> Option<String> {
-    let parts: Vec<&str> = intro.trim().split_whitespace().collect();
-    if parts.len() == 2 && parts[0] == "client" {
-        Some(parts[1].to_string())
-    } else {
-        None
+// An error frame saying why, then the connection closes
+fn refuse(connection: &Connection, cipher: &Aes256Gcm, refusal: String) {
+    if let Ok(packet) = frame::seal_frame(cipher, &Line::new("error").arg(refusal).to_string()) {
+        connection.send(packet.into());
     }
+    connection.close();
 }
 
-fn add_client_to_list(clients: &ClientList, username: String, stream: SharedStream) -> io::Result<()> {
-    let mut clients_lock = clients.lock().unwrap();
-    clients_lock.insert(username, stream);
-    Ok(())
+// Frames are queued under the client-list lock so every client gets them in the same order.
+// Queueing never blocks, the writer tasks do the actual writing.
+fn send_to_matching(clients: &ClientList, message: &[u8], matches: impl Fn(&str, &Client) -> bool) -> usize {
+    queue_for_matching(clients, message, None, matches)
 }
 
-fn broadcast_message(clients: &ClientList, sender_username: &str, message: &[u8]) -> io::Result<()> {
-    let mut disconnected_clients = vec![];
+// `key` marks a state update that a newer one with the same key replaces, see outbox.rs.
+// A client whose queue overflows under the disconnect policy is closed here and its
+// msg_fetcher cleans up. Returns how many took the frame, a policy drop counts.
+fn queue_for_matching(clients: &ClientList, message: &[u8], key: Option<&str>, matches: impl Fn(&str, &Client) -> bool) -> usize {
+    let packet: Packet = Arc::from(message);
     let clients_lock = clients.lock().unwrap();
-
-    for (username, client_stream) in clients_lock.iter() {
-        if username != sender_username {
-            if let Ok(mut stream) = TcpStream::try_clone(&**client_stream) {
-                if let Err(e) = stream.write_all(message) {
-                    eprintln!("Failed to send to {}: {}", username, e);
-                    disconnected_clients.push(username.clone());
-                }
+    let mut delivered = 0;
+    for (username, client) in clients_lock.iter().filter(|(name, client)| matches(name, client)) {
+        match client.connection.outbox.push(Arc::clone(&packet), key) {
+            Pushed::Queued | Pushed::Coalesced | Pushed::Dropped => delivered += 1,
+            Pushed::Overflow => {
+                println!("Disconnecting {}, it fell too far behind", username);
+                client.connection.close();
             }
+            Pushed::Closed => {}
         }
     }
-    drop(clients_lock);
+    delivered
+}
+
+fn send_line(clients: &ClientList, cipher: &Aes256Gcm, username: &str, line: &Line) -> io::Result<bool> {
+    let packet = frame::seal_frame(cipher, &line.to_string())?;
+    Ok(send_to_matching(clients, &packet, |name, _| name == username) > 0)
+}
+
+fn broadcast_message(clients: &ClientList, sender_username: &str, message: &[u8], key: Option<&str>) -> io::Result<()> {
+    let room = match clients.lock().unwrap().get(sender_username) {
+        Some(client) => client.room.clone(),
+        None => return Ok(()),
+    };
+    queue_for_matching(clients, message, key, |username, client| username != sender_username && client.room == room);
+    Ok(())
+}
+
+// Stores a room message and hands it to everyone else in the room.
+// The history lock is held until it's sent so every client sees ids in order.
+// @mentions of connected or registered users are stored with the message, the mentioned
+// get their copy tagged "highlight" and registered users who are offline get it queued.
+// Replies name their parent, which has to be a live message in the same room.
+// Returns the id the message was stored under.
+#[allow(clippy::too_many_arguments)]
+fn relay_to_room(clients: &ClientList, history: &SharedHistory, users: &SharedUsers, mailbox: &SharedMailbox, cipher: &Aes256Gcm, username: &str, kind: &str, text: &str, in_reply_to: Option<u64>) -> io::Result<Option<u64>> {
+    let room = match clients.lock().unwrap().get(username) {
+        Some(client) => client.room.clone(),
+        None => return Ok(None),
+    };
+    let (mentions, offline) = {
+        let clients_lock = clients.lock().unwrap();
+        let users_lock = users.lock().unwrap();
+        let known = mentioned_names(text)
+            .into_iter()
+            .filter(|name| clients_lock.contains_key(*name) || users_lock.is_registered(name));
+        let mentions: Vec<String> = known.map(str::to_string).collect();
+        let offline: Vec<String> = mentions.iter().filter(|name| !clients_lock.contains_key(*name)).cloned().collect();
+        (mentions, offline)
+    };
+
+    let mut history_lock = history.lock().unwrap();
+    let record = match history_lock.append(&room, kind, username, text, in_reply_to, mentions) {
+        Ok(record) => record,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            drop(history_lock);
+            return send_error(clients, cipher, username, &format!("can't reply, {} in this room", e)).map(|_| None);
+        }
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            drop(history_lock);
+            return send_error(clients, cipher, username, &e.to_string()).map(|_| None);
+        }
+        Err(e) => {
+            drop(history_lock);
+            eprintln!("Failed to store message from {}: {}", username, e);
+            return send_error(clients, cipher, username, "message could not be stored").map(|_| None);
+        }
+    };
+    let packet = frame::seal_frame(cipher, &record.to_line().to_string())?;
+    let highlighted = frame::seal_frame(cipher, &record.to_line().tag("highlight", "").to_string())?;
+    let mentioned = |name: &str| record.mentions.iter().any(|m| m == name);
+    send_to_matching(clients, &packet, |name, client| name != username && client.room == room && !mentioned(name));
+    send_to_matching(clients, &highlighted, |name, client| name != username && client.room == room && mentioned(name));
+    drop(history_lock);
+
+    let mut mailbox_lock = mailbox.lock().unwrap();
+    for name in &offline {
+        let queued = record_line(&record, name).tag("room", &room);
+        mailbox_lock.enqueue(name, queued);
+    }
+    Ok(Some(record.id))
+}
+
+// A stored message as `username` should see it, "highlight" when it mentions them
+fn record_line(record: &Record, username: &str) -> Line {
+    let line = record.to_line();
+    if record.mentions.iter().any(|m| m == username) { line.tag("highlight", "") } else { line }
+}
+
+// "ack <local id> <id>" tells the sender which server id their message got.
+// Clients pick the local id themselves and send it as the "local" tag.
+fn acknowledge(clients: &ClientList, cipher: &Aes256Gcm, username: &str, line: &Line, id: Option<u64>) -> io::Result<()> {
+    match (line.get_tag("local"), id) {
+        (Some(local), Some(id)) => send_line(clients, cipher, username, &Line::new("ack").arg(local).arg(id.to_string())).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+// Edits (Some(text)) or deletes (None) a stored message. Only its author may edit it,
+// moderators may delete anyone's.
+// The message's room gets "@edited=<ts> edit <id> :<text>" or "@ts=<ts> delete <id>".
+fn modify_message(clients: &ClientList, history: &SharedHistory, moderation: &SharedModeration, cipher: &Aes256Gcm, username: &str, id: u64, new_text: Option<&str>) -> io::Result<()> {
+    let moderator = new_text.is_none() && moderation.lock().unwrap().role(username) >= Role::Moderator;
+    let mut history_lock = history.lock().unwrap();
+    let refusal = match history_lock.get(id) {
+        None => Some(format!("no message with id {}", id)),
+        Some(record) if record.from != username && !moderator => Some("you can only change your own messages".to_string()),
+        Some(_) => None,
+    };
+    if let Some(refusal) = refusal {
+        drop(history_lock);
+        return send_error(clients, cipher, username, &refusal);
+    }
+
+    let changed = match new_text {
+        Some(text) => history_lock.edit(id, text),
+        None => history_lock.delete(id),
+    };
+    drop(history_lock);
+    let record = match changed {
+        Ok(record) => record,
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => return send_error(clients, cipher, username, &e.to_string()),
+        Err(e) => {
+            eprintln!("Failed to change message {}: {}", id, e);
+            return send_error(clients, cipher, username, "message could not be changed");
+        }
+    };
 
-    if !disconnected_clients.is_empty() {
+    let line = match record.edited {
+        Some(edited) if !record.deleted => Line::new("edit").tag("edited", edited).arg(id.to_string()).arg(&record.text),
+        _ => Line::new("delete").tag("ts", history::now()).arg(id.to_string()),
+    };
+    let packet = frame::seal_frame(cipher, &line.to_string())?;
+    send_to_matching(clients, &packet, |_, client| client.room == record.room);
+    Ok(())
+}
+
+// The message's room gets "@ts=..;by=<user> reaction <id> <emoji> <count>" with the new total
+fn update_reaction(clients: &ClientList, history: &SharedHistory, cipher: &Aes256Gcm, username: &str, id: u64, emoji: &str, add: bool) -> io::Result<()> {
+    let changed = history.lock().unwrap().react(id, username, emoji, add);
+    let record = match changed {
+        Ok((record, true)) => record,
+        Ok((_, false)) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return send_error(clients, cipher, username, &e.to_string()),
+        Err(e) => {
+            eprintln!("Failed to store reaction on {}: {}", id, e);
+            return send_error(clients, cipher, username, "reaction could not be stored");
+        }
+    };
+
+    let count = record.reactions.get(emoji).map_or(0, |users| users.len());
+    let line = Line::new("reaction").tag("ts", history::now()).tag("by", username).arg(id.to_string()).arg(emoji).arg(count.to_string());
+    let packet = frame::seal_frame(cipher, &line.to_string())?;
+    send_to_matching(clients, &packet, |_, client| client.room == record.room);
+    Ok(())
+}
+
+// "read <id> <reader>" goes back to whoever wrote message <id>, if they're around, once per reader.
+// A room message can only be marked read from its room, a direct message only by whoever it went to.
+// Receipts are best effort, they aren't stored or queued.
+fn forward_read_receipt(clients: &ClientList, history: &SharedHistory, cipher: &Aes256Gcm, reader: &str, id: u64) -> io::Result<()> {
+    let Some(room) = clients.lock().unwrap().get(reader).map(|c| c.room.clone()) else {
+        return Ok(());
+    };
+    let author = history.lock().unwrap().mark_read(id, reader, &room);
+    match author {
+        Ok(Some(author)) => send_line(clients, cipher, &author, &Line::new("read").arg(id.to_string()).arg(reader)).map(|_| ()),
+        Ok(None) => Ok(()),
+        Err(e) => send_error(clients, cipher, reader, &e.to_string()),
+    }
+}
+
+// Sends the room backlog, each frame tagged "replay" so the UI can tell it from live traffic.
+// Returns the ids that went out.
+fn replay_history(clients: &ClientList, history: &SharedHistory, cipher: &Aes256Gcm, username: &str, room: &str, since: Option<u64>) -> io::Result<Vec<u64>> {
+    let records = match since {
+        Some(id) => history.lock().unwrap().since(room, id, HISTORY_SINCE_MAX),
+        None => history.lock().unwrap().last(room, HISTORY_REPLAY),
+    };
+    for record in &records {
+        send_line(clients, cipher, username, &record_line(record, username).tag("replay", ""))?;
+    }
+    Ok(records.iter().map(|r| r.id).collect())
+}
+
+// "[@room=..;from=..;since=<ts>;until=<ts>;before=<id>;limit=<n>] search :<words>"
+// Answered with "[@more=<id>] results <count> :<words>" and a "hit" per match, newest first.
+// "more" is the id to send as "before" for the next page.
+fn search_history(clients: &ClientList, history: &SharedHistory, cipher: &Aes256Gcm, username: &str, line: &Line) -> io::Result<()> {
+    let number = |key: &str| line.get_tag(key).map(str::parse::<u64>).transpose();
+    let (Ok(since), Ok(until), Ok(before), Ok(limit)) = (number("since"), number("until"), number("before"), number("limit")) else {
+        return send_error(clients, cipher, username, "usage: [@room=<room>;from=<user>;since=<ts>;until=<ts>;before=<id>;limit=<n>] search :<words>");
+    };
+    let text = line.get(0).unwrap_or_default();
+    let query = SearchQuery {
+        words: text.split_whitespace().map(str::to_lowercase).collect(),
+        room: line.get_tag("room").map(str::to_string),
+        from: line.get_tag("from").map(str::to_string),
+        since,
+        until,
+        before,
+        limit: limit.map_or(SEARCH_PAGE, |limit| (limit as usize).clamp(1, SEARCH_PAGE_MAX)),
+        scan: SEARCH_SCAN,
+    };
+    if query.words.is_empty() && query.from.is_none() {
+        return send_error(clients, cipher, username, "search for some words or someone's messages");
+    }
+
+    let (records, more) = history.lock().unwrap().search(&query);
+    let mut header = Line::new("results").arg(records.len().to_string()).arg(text);
+    if let Some(before) = more {
+        header = header.tag("more", before);
+    }
+    send_line(clients, cipher, username, &header)?;
+    for record in &records {
+        let hit = Line::new("hit")
+            .tag("id", record.id)
+            .tag("ts", record.ts)
+            .tag("room", &record.room)
+            .arg(record.from.as_str())
+            .arg(history::excerpt(&record.text, &query.words, SEARCH_EXCERPT_CHARS));
+        send_line(clients, cipher, username, &hit)?;
+    }
+    Ok(())
+}
+
+// "thread <root> <count>" followed by the root message and all its replies, oldest first.
+// Any id in the thread works. The messages are tagged "replay" since they're not live.
+fn send_thread(clients: &ClientList, history: &SharedHistory, cipher: &Aes256Gcm, username: &str, id: u64) -> io::Result<()> {
+    let Some(records) = history.lock().unwrap().thread(id) else {
+        return send_error(clients, cipher, username, &format!("no message with id {}", id));
+    };
+    let root = records[0].id;
+    send_line(clients, cipher, username, &Line::new("thread").arg(root.to_string()).arg(records.len().to_string()))?;
+    for record in &records {
+        send_line(clients, cipher, username, &record_line(record, username).tag("replay", ""))?;
+    }
+    Ok(())
+}
+
+// "typing <user> start|stop" goes to the room as-is, never into history.
+// What gets this far has been through the user's TypingThrottle.
+fn relay_typing(clients: &ClientList, cipher: &Aes256Gcm, username: &str, state: &str) -> io::Result<()> {
+    let line = Line::new("typing").arg(username).arg(state);
+    let packet = frame::seal_frame(cipher, &line.to_string())?;
+    broadcast_message(clients, username, &packet, Some(&format!("typing {}", username)))
+}
+
+// Hands over whatever piled up while the user was away, tagged "queued".
+// Mentions the history replay already showed are skipped.
+fn deliver_mailbox(clients: &ClientList, mailbox: &SharedMailbox, cipher: &Aes256Gcm, username: &str, replayed: &[u64]) -> io::Result<()> {
+    let frames = {
+        mailbox.lock().unwrap().take(username)
+    };
+    for frame in frames {
+        let id = frame.get_tag("id").and_then(|id| id.parse::<u64>().ok());
+        if id.is_some_and(|id| replayed.contains(&id)) {
+            continue;
+        }
+        send_line(clients, cipher, username, &frame.tag("queued", ""))?;
+    }
+    Ok(())
+}
+
+// Pushes "presence <user> online|offline" to everyone else on the server
+fn announce_presence(clients: &ClientList, cipher: &Aes256Gcm, username: &str, state: &str) {
+    let line = Line::new("presence").tag("ts", history::now()).arg(username).arg(state);
+    match frame::seal_frame(cipher, &line.to_string()) {
+        Ok(packet) => {
+            queue_for_matching(clients, &packet, Some(&format!("presence {}", username)), |name, _| name != username);
+        }
+        Err(e) => eprintln!("Failed to announce {}: {}", username, e),
+    }
+}
+
+fn roster(clients: &ClientList) -> Line {
+    roster_of(clients.lock().unwrap().keys())
+}
+
+// "roster <user> <user> ...", sorted so the UI doesn't have to
+fn roster_of<'a>(names: impl Iterator<Item = &'a String>) -> Line {
+    let mut names: Vec<&String> = names.collect();
+    names.sort();
+    names.into_iter().fold(Line::new("roster"), |line, name| line.arg(name))
+}
+
+// Re-keys the client under one lock so nobody can grab the name in between,
+// then tells everyone (the renamed client included) "nick <old> <new>"
+// The new name is claimed for the user's key, they keep the old one too. Names of another
+// key are taken even while offline, and so are ones that hold a role or a ban.
+#[allow(clippy::too_many_arguments)]
+fn change_nick(clients: &ClientList, users: &SharedUsers, transfers: &SharedTransfers, moderation: &SharedModeration, cipher: &Aes256Gcm, user_key: &UserKey, username: &mut String, new_name: &str) -> io::Result<()> {
+    if new_name == username {
+        return Ok(());
+    }
+    let reserved = {
+        let moderation_lock = moderation.lock().unwrap();
+        moderation_lock.role(new_name) != Role::Member || moderation_lock.is_banned_name(new_name)
+    };
+    if reserved {
+        return send_error(clients, cipher, username, &format!("{} is reserved", new_name));
+    }
+    {
         let mut clients_lock = clients.lock().unwrap();
-        for username in disconnected_clients {
-            clients_lock.remove(&username);
-            println!("Removed disconnected client: {}", username);
+        if let Err(refusal) = users.lock().unwrap().claim(new_name, user_key) {
+            drop(clients_lock);
+            return send_error(clients, cipher, username, &refusal);
+        }
+        if clients_lock.contains_key(new_name) {
+            drop(clients_lock);
+            return send_error(clients, cipher, username, &format!("{} is already taken", new_name));
+        }
+        let Some(client) = clients_lock.remove(username.as_str()) else {
+            return Ok(());
+        };
+        clients_lock.insert(new_name.to_string(), client);
+    }
+    println!("Client {} is now {}", username, new_name);
+    transfers.lock().unwrap().rename(username, new_name);
+    moderation.lock().unwrap().rename(username, new_name);
+
+    let line = Line::new("nick").tag("ts", history::now()).arg(username.as_str()).arg(new_name);
+    *username = new_name.to_string();
+    let packet = frame::seal_frame(cipher, &line.to_string())?;
+    send_to_matching(clients, &packet, |_, _| true);
+    Ok(())
+}
+
+// Delivers a dm frame to exactly one connection. If they're not here it waits in their
+// mailbox when they're registered, otherwise the sender gets an error frame.
+// Direct messages get an id like room messages, returned so the sender can be acked.
+#[allow(clippy::too_many_arguments)]
+fn send_private_message(clients: &ClientList, history: &SharedHistory, users: &SharedUsers, mailbox: &SharedMailbox, cipher: &Aes256Gcm, sender_username: &str, recipient: &str, text: &str) -> io::Result<Option<u64>> {
+    if let Err(e) = history::check_text(text) {
+        return send_error(clients, cipher, sender_username, &e.to_string()).map(|_| None);
+    }
+    let online = clients.lock().unwrap().contains_key(recipient);
+    if !online && !users.lock().unwrap().is_registered(recipient) {
+        return send_error(clients, cipher, sender_username, &format!("{} is not online", recipient)).map(|_| None);
+    }
+    let id = history.lock().unwrap().private(sender_username, recipient);
+
+    let out = Line::new("dm").tag("id", id).tag("ts", history::now()).arg(sender_username).arg(text);
+    if send_line(clients, cipher, recipient, &out)? {
+        return Ok(Some(id));
+    }
+    mailbox.lock().unwrap().enqueue(recipient, out);
+    let notice = format!("{} is offline, they'll get your message when they're back", recipient);
+    send_line(clients, cipher, sender_username, &Line::new("system").arg(notice))?;
+    Ok(Some(id))
+}
+
+// "offer <size> <sha256> :<name>" offers a file to the sender's room, or with "@to=<user>"
+// to one user. Recipients get "@id=<transfer>;ts=..[;private] offer <from> <size> <sha256> :<name>",
+// the sender "offered <transfer>" carrying their "local" tag.
+fn offer_file(clients: &ClientList, transfers: &SharedTransfers, cipher: &Aes256Gcm, username: &str, line: &Line) -> io::Result<()> {
+    let size = line.get(0).and_then(|size| size.parse::<u64>().ok());
+    let sha256 = line.get(1).filter(|hash| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()));
+    let name = line.get(2).filter(|name| !name.is_empty());
+    let (Some(size), Some(sha256), Some(name)) = (size, sha256, name) else {
+        return send_error(clients, cipher, username, "usage: offer <size> <sha256> :<name>");
+    };
+    let to = line.get_tag("to").map(str::to_string);
+    let room = {
+        let clients_lock = clients.lock().unwrap();
+        if let Some(to) = &to
+            && (to == username || !clients_lock.contains_key(to))
+        {
+            drop(clients_lock);
+            return send_error(clients, cipher, username, &format!("{} is not online", to));
+        }
+        match clients_lock.get(username) {
+            Some(client) => client.room.clone(),
+            None => return Ok(()),
+        }
+    };
+
+    let sha256 = sha256.to_lowercase();
+    let id = transfers.lock().unwrap().offer(Offer {
+        from: username.to_string(),
+        room: room.clone(),
+        to: to.clone(),
+        size,
+        accepted: Default::default(),
+    });
+    println!("{} offered '{}' ({} bytes) as transfer {}", username, name, size, id);
+
+    let mut out = Line::new("offer").tag("id", id).tag("ts", history::now());
+    if to.is_some() {
+        out = out.tag("private", "");
+    }
+    let out = out.arg(username).arg(size.to_string()).arg(sha256).arg(name);
+
+    let packet = frame::seal_frame(cipher, &out.to_string())?;
+    match &to {
+        Some(to) => send_to_matching(clients, &packet, |name, _| name == to),
+        None => send_to_matching(clients, &packet, |name, client| name != username && client.room == room),
+    };
+    let mut reply = Line::new("offered");
+    if let Some(local) = line.get_tag("local") {
+        reply = reply.tag("local", local);
+    }
+    send_line(clients, cipher, username, &reply.arg(id.to_string())).map(|_| ())
+}
+
+// "accept <transfer> <offset>" reaches the sender as "accept <transfer> <user> <offset>".
+// A non-zero offset resumes a download that was cut off part way.
+fn accept_file(clients: &ClientList, transfers: &SharedTransfers, cipher: &Aes256Gcm, username: &str, id: u64, offset: u64) -> io::Result<()> {
+    let room = match clients.lock().unwrap().get(username) {
+        Some(client) => client.room.clone(),
+        None => return Ok(()),
+    };
+    let sender = match transfers.lock().unwrap().get_mut(id) {
+        Some(offer) if offer.is_for(username, &room) && offset <= offer.size => {
+            offer.accepted.insert(username.to_string());
+            Some(offer.from.clone())
         }
+        _ => None,
+    };
+    match sender {
+        Some(sender) => {
+            let line = Line::new("accept").arg(id.to_string()).arg(username).arg(offset.to_string());
+            send_line(clients, cipher, &sender, &line).map(|_| ())
+        }
+        None => send_error(clients, cipher, username, &format!("no file offer {} for you", id)),
     }
+}
 
+// "reject <transfer>" declines an offer or stops a download that's under way,
+// the sender gets "reject <transfer> <user>". Only someone the offer is for can decline it,
+// a download already under way can be stopped from any room.
+fn reject_file(clients: &ClientList, transfers: &SharedTransfers, cipher: &Aes256Gcm, username: &str, id: u64) -> io::Result<()> {
+    let room = match clients.lock().unwrap().get(username) {
+        Some(client) => client.room.clone(),
+        None => return Ok(()),
+    };
+    let sender = match transfers.lock().unwrap().get_mut(id) {
+        Some(offer) if offer.accepted.contains(username) || offer.is_for(username, &room) => {
+            offer.accepted.remove(username);
+            Some(offer.from.clone())
+        }
+        _ => None,
+    };
+    match sender {
+        Some(sender) => send_line(clients, cipher, &sender, &Line::new("reject").arg(id.to_string()).arg(username)).map(|_| ()),
+        None => send_error(clients, cipher, username, &format!("no file offer {} for you", id)),
+    }
+}
+
+// "chunk <transfer> <user> <offset> :<base64>" and "done <transfer> <user>" from the sender go to
+// <user> without the user argument, only while they've accepted. If <user> can't be reached
+// the sender gets "cancel <transfer> <user>" and should stop.
+// Chunks wait for room in the receiver's queue, so a slow download holds up its uploader
+// and nobody else.
+async fn relay_file_data(clients: &ClientList, transfers: &SharedTransfers, cipher: &Aes256Gcm, username: &str, line: &Line) -> io::Result<()> {
+    let arity = if line.cmd == "chunk" { 4 } else { 2 };
+    let id = line.get(0).and_then(|id| id.parse::<u64>().ok());
+    let (Some(id), Some(receiver), true) = (id, line.get(1), line.args.len() == arity) else {
+        let usage = if line.cmd == "chunk" { "usage: chunk <transfer> <user> <offset> :<data>" } else { "usage: done <transfer> <user>" };
+        return send_error(clients, cipher, username, usage);
+    };
+    let cancel = Line::new("cancel").arg(id.to_string()).arg(receiver);
+    let accepted = transfers.lock().unwrap().get(id).is_some_and(|offer| offer.from == username && offer.accepted.contains(receiver));
+    if !accepted {
+        return send_line(clients, cipher, username, &cancel).map(|_| ());
+    }
+
+    let receiving = clients.lock().unwrap().get(receiver).map(|client| client.connection.clone());
+    if let Some(connection) = receiving
+        && time::timeout(WRITE_TIMEOUT, connection.outbox.room()).await.is_err()
+    {
+        connection.close();
+    }
+
+    let mut out = Line::new(&line.cmd);
+    for (i, arg) in line.args.iter().enumerate() {
+        if i != 1 {
+            out = out.arg(arg.as_str());
+        }
+    }
+    if !send_line(clients, cipher, receiver, &out)? {
+        if let Some(offer) = transfers.lock().unwrap().get_mut(id) {
+            offer.accepted.remove(receiver);
+        }
+        send_line(clients, cipher, username, &cancel)?;
+    }
     Ok(())
 }
 
-fn disconnect_client(clients: &ClientList, username: &str, stream: SharedStream) -> io::Result<()> {
+// Moderators act on members, the owner on everyone else. With no target it only checks
+// that `actor` moderates at all.
+fn check_authority(moderation: &SharedModeration, actor: &str, target: Option<&str>) -> Result<(), String> {
+    let moderation_lock = moderation.lock().unwrap();
+    let actor_role = moderation_lock.role(actor);
+    if actor_role < Role::Moderator {
+        return Err("only moderators can do that".to_string());
+    }
+    match target {
+        Some(target) if target == actor => Err("you can't do that to yourself".to_string()),
+        Some(target) if moderation_lock.role(target) >= actor_role => {
+            Err(format!("{} is a {}, you can't do that", target, moderation_lock.role(target)))
+        }
+        _ => Ok(()),
+    }
+}
+
+// "kick <user> :<reason>", they get an error frame saying why and are disconnected
+fn kick_user(clients: &ClientList, moderation: &SharedModeration, cipher: &Aes256Gcm, actor: &str, target: &str, reason: &str) -> io::Result<()> {
+    if let Err(refusal) = check_authority(moderation, actor, Some(target)) {
+        return send_error(clients, cipher, actor, &refusal);
+    }
+    let notice = with_reason(format!("you were kicked by {}", actor), reason);
+    if disconnect_matching(clients, cipher, &notice, |name, _| name == target)?.is_empty() {
+        return send_error(clients, cipher, actor, &format!("{} is not online", target));
+    }
+    announce(clients, cipher, &with_reason(format!("{} was kicked by {}", target, actor), reason))
+}
+
+// "ban <user|ip> <duration> :<reason>", stored and enforced from now on. Whoever it
+// matches is disconnected straight away, an address ban needs authority over all of them.
+fn ban_target(clients: &ClientList, moderation: &SharedModeration, cipher: &Aes256Gcm, actor: &str, target: &str, duration: Option<u64>, reason: &str) -> io::Result<()> {
+    let target = BanTarget::parse(target);
+    let affected: Vec<String> = match &target {
+        BanTarget::User(name) => vec![name.clone()],
+        BanTarget::Ip(ip) => clients.lock().unwrap().iter().filter(|(_, c)| c.ip == *ip).map(|(name, _)| name.clone()).collect(),
+    };
+    let refusal = match &target {
+        BanTarget::User(name) if !is_valid_name(name) => Some("usage: ban <user|ip> <duration> :<reason>".to_string()),
+        _ => check_authority(moderation, actor, None).err(),
+    };
+    let refusal = refusal.or_else(|| affected.iter().find_map(|name| check_authority(moderation, actor, Some(name)).err()));
+    if let Some(refusal) = refusal {
+        return send_error(clients, cipher, actor, &refusal);
+    }
+
+    let ban = Ban {
+        target: target.clone(),
+        until: duration.map(|secs| history::now().saturating_add(secs)),
+        by: actor.to_string(),
+        reason: reason.to_string(),
+    };
+    moderation.lock().unwrap().ban(ban);
+    println!("{} banned {} {}", actor, target, describe_duration(duration));
+
+    let notice = with_reason(format!("you were banned by {} {}", actor, describe_duration(duration)), reason);
+    let gone = disconnect_matching(clients, cipher, &notice, |name, client| match &target {
+        BanTarget::User(banned) => name == banned,
+        BanTarget::Ip(ip) => client.ip == *ip,
+    })?;
+    for name in &gone {
+        announce(clients, cipher, &with_reason(format!("{} was banned by {} {}", name, actor, describe_duration(duration)), reason))?;
+    }
+    if gone.is_empty() {
+        send_line(clients, cipher, actor, &Line::new("system").arg(format!("{} is banned {}", target, describe_duration(duration))))?;
+    }
+    Ok(())
+}
+
+fn unban_target(clients: &ClientList, moderation: &SharedModeration, cipher: &Aes256Gcm, actor: &str, target: &str) -> io::Result<()> {
+    if let Err(refusal) = check_authority(moderation, actor, None) {
+        return send_error(clients, cipher, actor, &refusal);
+    }
+    let target = BanTarget::parse(target);
+    if !moderation.lock().unwrap().unban(&target) {
+        return send_error(clients, cipher, actor, &format!("{} isn't banned", target));
+    }
+    println!("{} unbanned {}", actor, target);
+    send_line(clients, cipher, actor, &Line::new("system").arg(format!("{} is no longer banned", target))).map(|_| ())
+}
+
+// "mute <user> <duration>" (Some) or "unmute <user>" (None), muted users can't talk but still read
+fn mute_user(clients: &ClientList, moderation: &SharedModeration, cipher: &Aes256Gcm, actor: &str, target: &str, duration: Option<Option<u64>>) -> io::Result<()> {
+    if let Err(refusal) = check_authority(moderation, actor, Some(target)) {
+        return send_error(clients, cipher, actor, &refusal);
+    }
+    let text = match duration {
+        Some(duration) => {
+            moderation.lock().unwrap().mute(target, duration.map(|secs| history::now().saturating_add(secs)));
+            format!("{} was muted by {} {}", target, actor, describe_duration(duration))
+        }
+        None if moderation.lock().unwrap().unmute(target) => format!("{} was unmuted by {}", target, actor),
+        None => return send_error(clients, cipher, actor, &format!("{} isn't muted", target)),
+    };
+    announce(clients, cipher, &text)
+}
+
+// "op <user>" / "deop <user>", only the owner hands out or takes back moderator rights
+// Only names someone has claimed, a role on a free name would go to whoever takes it first
+#[allow(clippy::too_many_arguments)]
+fn set_moderator(clients: &ClientList, users: &SharedUsers, moderation: &SharedModeration, cipher: &Aes256Gcm, actor: &str, target: &str, moderator: bool) -> io::Result<()> {
+    let changed = {
+        let mut moderation_lock = moderation.lock().unwrap();
+        if moderation_lock.role(actor) != Role::Owner {
+            Err("only the owner can do that".to_string())
+        } else if moderation_lock.role(target) == Role::Owner {
+            Err(format!("{} is the owner", target))
+        } else if moderator && !users.lock().unwrap().is_registered(target) {
+            Err(format!("{} has never been here", target))
+        } else {
+            let role = if moderator { Role::Moderator } else { Role::Member };
+            moderation_lock.set_role(target, role);
+            Ok(())
+        }
+    };
+    match changed {
+        Ok(()) if moderator => announce(clients, cipher, &format!("{} is now a moderator", target)),
+        Ok(()) => announce(clients, cipher, &format!("{} is no longer a moderator", target)),
+        Err(refusal) => send_error(clients, cipher, actor, &refusal),
+    }
+}
+
+// Sends every matching client an error frame with `notice` and closes their connection,
+// their msg_fetcher cleans up as usual. Returns who was disconnected.
+fn disconnect_matching(clients: &ClientList, cipher: &Aes256Gcm, notice: &str, matches: impl Fn(&str, &Client) -> bool) -> io::Result<Vec<String>> {
+    let packet: Packet = frame::seal_frame(cipher, &Line::new("error").arg(notice).to_string())?.into();
+    let clients_lock = clients.lock().unwrap();
+    let mut gone = Vec::new();
+    for (username, client) in clients_lock.iter().filter(|(name, client)| matches(name, client)) {
+        client.connection.send(Arc::clone(&packet));
+        client.connection.close();
+        gone.push(username.clone());
+    }
+    Ok(gone)
+}
+
+// "@ts=.. system :<text>" to everyone on the server
+fn announce(clients: &ClientList, cipher: &Aes256Gcm, text: &str) -> io::Result<()> {
+    let line = Line::new("system").tag("ts", history::now()).arg(text);
+    send_to_matching(clients, &frame::seal_frame(cipher, &line.to_string())?, |_, _| true);
+    Ok(())
+}
+
+fn ban_notice(ban: &Ban) -> String {
+    let left = ban.until.map(|until| until.saturating_sub(history::now()));
+    with_reason(format!("you are banned from this server {}", describe_duration(left)), &ban.reason)
+}
+
+fn with_reason(text: String, reason: &str) -> String {
+    if reason.is_empty() { text } else { format!("{}: {}", text, reason) }
+}
+
+fn describe_duration(secs: Option<u64>) -> String {
+    match secs {
+        None => "for good".to_string(),
+        Some(secs) => format!("for {}", short_duration(secs)),
+    }
+}
+
+fn short_duration(secs: u64) -> String {
+    match secs {
+        secs if secs < 60 => format!("{}s", secs),
+        secs if secs.div_ceil(60) < 60 => format!("{}m", secs.div_ceil(60)),
+        secs if secs.div_ceil(60 * 60) < 24 => format!("{}h", secs.div_ceil(60 * 60)),
+        secs => format!("{}d", secs.div_ceil(24 * 60 * 60)),
+    }
+}
+
+fn send_motd(clients: &ClientList, motd: &SharedMotd, cipher: &Aes256Gcm, username: &str) -> io::Result<()> {
+    let lines = motd.lock().unwrap().lines().to_vec();
+    for text in lines {
+        send_line(clients, cipher, username, &Line::new("system").tag("motd", "").arg(text))?;
+    }
+    Ok(())
+}
+
+// Owner only. Everyone online sees the new one straight away.
+fn set_motd(clients: &ClientList, moderation: &SharedModeration, motd: &SharedMotd, cipher: &Aes256Gcm, actor: &str, text: &str) -> io::Result<()> {
+    if moderation.lock().unwrap().role(actor) != Role::Owner {
+        return send_error(clients, cipher, actor, "only the owner can do that");
+    }
+    motd.lock().unwrap().set(text);
+    println!("{} changed the MOTD", actor);
+    if text.trim().is_empty() {
+        return announce(clients, cipher, &format!("{} cleared the message of the day", actor));
+    }
+    announce(clients, cipher, &format!("{} changed the message of the day", actor))?;
+    let names: Vec<String> = clients.lock().unwrap().keys().cloned().collect();
+    for name in names {
+        send_motd(clients, motd, cipher, &name)?;
+    }
+    Ok(())
+}
+
+// Tags pick the room (default: where we are), "expires=<30m|2h|7d|perm>" and "uses=<n>"
+async fn create_invite(clients: &ClientList, lobby: &Lobby, cipher: &Aes256Gcm, username: &str, line: &Line) -> io::Result<()> {
+    let current_room = clients.lock().unwrap().get(username).map(|c| c.room.clone()).unwrap_or_default();
+    let room = line.get_tag("room").unwrap_or(&current_room);
+    let expiry = match line.get_tag("expires") {
+        Some(expires) => moderation::parse_duration(expires),
+        None => Some(Some(INVITE_EXPIRY_SECS)),
+    };
+    let uses = match line.get_tag("uses") {
+        Some(uses) => uses.parse::<u32>().ok().filter(|uses| *uses > 0).map(Some),
+        None => Some(None),
+    };
+    let (Some(expiry), Some(uses)) = (expiry, uses) else {
+        return send_error(clients, cipher, username, "usage: [@room=<room>;expires=<30m|2h|7d|perm>;uses=<n>] invite");
+    };
+    if !is_valid_name(room) {
+        return send_error(clients, cipher, username, "that's not a room name");
+    }
+
+    let code = lobby::new_invite_code();
+    let registered = time::timeout(LOBBY_TIMEOUT, lobby.register_invite(&code, room, expiry.map(|secs| history::now().saturating_add(secs)), uses)).await;
+    match registered.unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "the lobby didn't answer"))) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            eprintln!("The lobby refused invite {}: {}", code, e);
+            return send_error(clients, cipher, username, &format!("the lobby refused the invite: {}", e));
+        }
+        Err(e) => {
+            eprintln!("Failed to register invite with the lobby: {}", e);
+            return send_error(clients, cipher, username, "the lobby could not be reached, try again later");
+        }
+    }
+    println!("{} made invite {} for {}", username, code, room);
+    let uses = uses.map_or("any number of uses".to_string(), |uses| format!("{} use{}", uses, if uses == 1 { "" } else { "s" }));
+    let expires = match expiry {
+        Some(secs) => format!("expires in {}", short_duration(secs)),
+        None => "never expires".to_string(),
+    };
+    let text = format!("invite code {} for {}, {}, {}", code, room, expires, uses);
+    send_line(clients, cipher, username, &Line::new("system").tag("invite", code).arg(text)).map(|_| ())
+}
+
+fn switch_room(clients: &ClientList, history: &SharedHistory, cipher: &Aes256Gcm, username: &str, room: &str) -> io::Result<()> {
+    if let Some(client) = clients.lock().unwrap().get_mut(username) {
+        client.room = room.to_string();
+    }
+    send_line(clients, cipher, username, &Line::new("room").arg(room))?;
+    replay_history(clients, history, cipher, username, room, None).map(|_| ())
+}
+
+fn send_error(clients: &ClientList, cipher: &Aes256Gcm, username: &str, text: &str) -> io::Result<()> {
+    send_line(clients, cipher, username, &Line::new("error").arg(text)).map(|_| ())
+}
+
+// Returns whether this connection was still the one listed under `username`,
+// a newer login with the same name must not be kicked out by the old one leaving
+fn disconnect_client(clients: &ClientList, username: &str, connection: &Connection) -> bool {
     let mut clients_lock = clients.lock().unwrap();
-    if clients_lock.remove(username).is_some() {
+    let removed = match clients_lock.get(username) {
+        Some(client) if client.connection.is(connection) => clients_lock.remove(username).is_some(),
+        _ => false,
+    };
+    drop(clients_lock);
+    if removed {
         println!("Client {} disconnected", username);
     }
-    stream.shutdown(Shutdown::Both)?;
-    Ok(())
+    removed
 }
 
-fn main() -> io::Result<()> {
+// Every connection is a task on a small thread pool, an idle client costs a few KB
+#[tokio::main]
+async fn main() -> io::Result<()> {
     print!("Enter lobby address: ");
     io::stdout().flush()?;
     let mut lobby_addr = String::new();
@@ -197,29 +1223,82 @@ fn main() -> io::Result<()> {
     io::stdin().read_line(&mut serv_name)?;
     let serv_name = serv_name.trim().to_string();
 
+    print!("Password for this server (empty for none): ");
+    io::stdout().flush()?;
+    let mut password = String::new();
+    io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    let server_key = Arc::new(if password.is_empty() { ServerKey::random() } else { ServerKey::from_password(password) });
+
     let serv_ip = get_ip();
     // let serv_ip = "0.tcp.eu.ngrok.io:14770";
 
-    let mut to_lobby = TcpStream::connect(lobby_addr).expect("Could not connect to lobby");
-    writeln!(to_lobby, "server {} {}", serv_ip, serv_name).expect("Failed to register with lobby");
-    to_lobby.shutdown(Shutdown::Write).ok();
-
-    let aes_key = Arc::new(generate_aes_key());
+    let lobby = Arc::new(Lobby { addr: lobby_addr, serv_ip: serv_ip.clone() });
+    lobby.register(&serv_name, server_key.is_locked()).await.expect("Failed to register with lobby");
 
-    let listener = TcpListener::bind("0.0.0.0:8081")?;
+    let listener = TcpListener::bind(("0.0.0.0", SERVER_PORT)).await?;
     let clients: ClientList = Arc::new(Mutex::new(HashMap::new()));
+    let disk = Disk::start();
+    let history: SharedHistory = Arc::new(Mutex::new(History::open(Path::new(HISTORY_FILE), disk.clone())?));
+    let users: SharedUsers = Arc::new(Mutex::new(Users::open(Path::new(USERS_FILE), disk.clone())?));
+    let mailbox: SharedMailbox = Arc::new(Mutex::new(Mailbox::open(Path::new(MAILBOX_FILE), disk.clone())?));
+    let transfers: SharedTransfers = Arc::new(Mutex::new(Transfers::new()));
+    let mut moderation = Moderation::open(Path::new(ROLES_FILE), Path::new(BANS_FILE), disk.clone())?;
+    if !moderation.has_owner() {
+        // The key pins the name to the owner's client, it prints it as "Identity key" on start
+        print!("No owner yet, who owns this server? (username and identity key, empty to skip): ");
+        io::stdout().flush()?;
+        let mut owner = String::new();
+        io::stdin().read_line(&mut owner)?;
+        let mut words = owner.split_whitespace();
+        if let Some(owner) = words.next().filter(|owner| is_valid_name(owner)) {
+            let key = words.next().and_then(|key| hex::decode(key).ok()).and_then(|key| UserKey::try_from(key).ok());
+            match key {
+                Some(key) => match users.lock().unwrap().claim(owner, &key) {
+                    Ok(()) => moderation.set_role(owner, Role::Owner),
+                    Err(refusal) => println!("{} can't be the owner: {}", owner, refusal),
+                },
+                None if users.lock().unwrap().is_registered(owner) => moderation.set_role(owner, Role::Owner),
+                None => {
+                    moderation.set_role(owner, Role::Owner);
+                    println!("{} hasn't been here yet, whoever joins under that name first becomes the owner", owner);
+                }
+            }
+        }
+    }
+    let moderation: SharedModeration = Arc::new(Mutex::new(moderation));
+    let motd: SharedMotd = Arc::new(Mutex::new(Motd::open(Path::new(MOTD_FILE), disk)?));
+
+    let limits = *ratelimit::LIMITS;
+    println!(
+        "Rate limits per client: {} messages/s (burst {}), {} bytes/s (burst {}), {} bulk bytes/s (burst {})",
+        limits.messages_per_sec, limits.message_burst, limits.bytes_per_sec, limits.byte_burst, limits.bulk_per_sec, limits.bulk_burst
+    );
+    let queues = *outbox::QUEUES;
+    println!("Outbound queue per client: {} frames, {} on overflow", queues.capacity, format!("{:?}", queues.overflow).to_lowercase());
 
-    println!("Server '{}' is running at {}:8081", serv_name, serv_ip);
+    println!("Server '{}' is running at {}:{}", serv_name, serv_ip, SERVER_PORT);
 
-    for stream in listener.incoming() {
-        let stream = stream?;
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                // Usually out of file descriptors, which passes as clients leave
+                eprintln!("Failed to accept a connection: {}", e);
+                time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
         let clients = Arc::clone(&clients);
-        let aes_key_clone = Arc::clone(&aes_key);
+        let history = Arc::clone(&history);
+        let users = Arc::clone(&users);
+        let mailbox = Arc::clone(&mailbox);
+        let transfers = Arc::clone(&transfers);
+        let moderation = Arc::clone(&moderation);
+        let motd = Arc::clone(&motd);
+        let lobby = Arc::clone(&lobby);
+        let server_key = Arc::clone(&server_key);
 
-        thread::spawn(move || {
-            msg_fetcher(stream, clients, aes_key_clone);
-        });
+        tokio::spawn(msg_fetcher(stream, clients, history, users, mailbox, transfers, moderation, motd, lobby, server_key));
     }
-
-    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,81 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+pub type SharedTransfers = Arc<Mutex<Transfers>>;
+
+// A file someone offered, to a room or to one user. The bytes never touch the server's
+// disk, chunks are relayed as they arrive and only metadata is kept, in memory,
+// for as long as the sender is connected. Checking the hash is up to the receiving client.
+pub struct Offer {
+    pub from: String,
+    pub room: String,       // room it was offered in, for room offers
+    pub to: Option<String>, // the one user it's for, for direct offers
+    pub size: u64,
+    pub accepted: HashSet<String>,
+}
+
+impl Offer {
+    // A direct offer is for its recipient only, a room offer for anyone currently in the room
+    pub fn is_for(&self, username: &str, room: &str) -> bool {
+        match &self.to {
+            Some(to) => to == username,
+            None => self.room == room && self.from != username,
+        }
+    }
+}
+
+pub struct Transfers {
+    offers: HashMap<u64, Offer>,
+    next_id: u64,
+}
+
+impl Transfers {
+    pub fn new() -> Self {
+        Transfers { offers: HashMap::new(), next_id: 1 }
+    }
+
+    pub fn offer(&mut self, offer: Offer) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.offers.insert(id, offer);
+        id
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Offer> {
+        self.offers.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Offer> {
+        self.offers.get_mut(&id)
+    }
+
+    // The sender is gone, nothing they offered can be fetched any more.
+    // Returns each dropped offer with whoever was still downloading it.
+    pub fn remove_from(&mut self, username: &str) -> Vec<(u64, Vec<String>)> {
+        let mut removed = Vec::new();
+        self.offers.retain(|id, offer| {
+            if offer.from != username {
+                return true;
+            }
+            removed.push((*id, offer.accepted.drain().collect()));
+            false
+        });
+        removed
+    }
+
+    pub fn rename(&mut self, old: &str, new: &str) {
+        for offer in self.offers.values_mut() {
+            if offer.from == old {
+                offer.from = new.to_string();
+            }
+            if offer.to.as_deref() == Some(old) {
+                offer.to = Some(new.to_string());
+            }
+            if offer.accepted.remove(old) {
+                offer.accepted.insert(new.to_string());
+            }
+        }
+    }
+}
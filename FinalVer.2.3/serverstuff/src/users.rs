@@ -0,0 +1,116 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use nameless_proto::auth::UserKey;
+use crate::disk::Disk;
+
+pub type SharedUsers = Arc<Mutex<Users>>;
+
+const NAMES_PER_KEY: usize = 5; // the file is never trimmed, so each key only gets a few
+
+// Which key owns which name. The first key to join under a name claims it, after that only
+// that key gets in as it, so roles, bans and queued messages stay with the same person.
+// One key can hold up to NAMES_PER_KEY names, /nick claims the new one and keeps the old.
+// The users file is only appended to: "<user> <hex key>"
+pub struct Users {
+    disk: Disk,
+    path: PathBuf,
+    keys: HashMap<String, UserKey>,
+}
+
+impl Users {
+    pub fn open(path: &Path, disk: Disk) -> io::Result<Users> {
+        let mut keys = HashMap::new();
+        if let Ok(existing) = File::open(path) {
+            for line in BufReader::new(existing).lines() {
+                let line = line?;
+                let claim = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                    [user, key] => hex::decode(key).ok().and_then(|key| key.try_into().ok()).map(|key: UserKey| (user.to_string(), key)),
+                    _ => None,
+                };
+                match claim {
+                    // The first claim stands, a later line for the same name can only be a mistake
+                    Some((user, key)) => {
+                        keys.entry(user).or_insert(key);
+                    }
+                    None if line.split_whitespace().count() <= 1 => {} // a name from before keys, free to claim
+                    None => eprintln!("Skipping unreadable users line: {}", line),
+                }
+            }
+        }
+        Ok(Users { disk, path: path.to_path_buf(), keys })
+    }
+
+    // Err says why `key` can't have the name
+    pub fn claim(&mut self, username: &str, key: &UserKey) -> Result<(), String> {
+        match self.keys.get(username) {
+            Some(owner) if owner == key => Ok(()),
+            Some(_) => Err(format!("{} belongs to someone else", username)),
+            None if self.keys.values().filter(|owner| *owner == key).count() >= NAMES_PER_KEY => {
+                Err(format!("you already have {} names here", NAMES_PER_KEY))
+            }
+            None => {
+                self.disk.append(&self.path, format!("{} {}", username, hex::encode(key)));
+                self.keys.insert(username.to_string(), *key);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn is_registered(&self, username: &str) -> bool {
+        self.keys.contains_key(username)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    fn names_stay_with_the_first_key() {
+        let path = env::temp_dir().join(format!("nameless-users-{}.txt", process::id()));
+        fs::remove_file(&path).ok();
+        let (alice, mallory) = ([1u8; 32], [2u8; 32]);
+        let disk = Disk::start();
+
+        let mut users = Users::open(&path, disk.clone()).unwrap();
+        assert!(!users.is_registered("alice"));
+        assert!(users.claim("alice", &alice).is_ok());
+        assert!(users.claim("alice", &alice).is_ok());
+        assert!(users.claim("alice", &mallory).is_err());
+        assert!(users.claim("al", &alice).is_ok());
+        drop(users);
+        disk.flush();
+
+        let mut users = Users::open(&path, disk.clone()).unwrap();
+        assert!(users.is_registered("alice") && users.is_registered("al"));
+        assert!(users.claim("alice", &mallory).is_err());
+        assert!(users.claim("alice", &alice).is_ok());
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn keys_only_get_a_few_names() {
+        let path = env::temp_dir().join(format!("nameless-users-cap-{}.txt", process::id()));
+        fs::remove_file(&path).ok();
+        let disk = Disk::start();
+        let mut users = Users::open(&path, disk.clone()).unwrap();
+        for n in 0..NAMES_PER_KEY {
+            assert!(users.claim(&format!("name{}", n), &[1u8; 32]).is_ok());
+        }
+        assert!(users.claim("onemore", &[1u8; 32]).is_err());
+        assert!(users.claim("name0", &[1u8; 32]).is_ok());
+        assert!(users.claim("onemore", &[2u8; 32]).is_ok());
+        drop(users);
+        disk.flush();
+
+        let mut users = Users::open(&path, disk.clone()).unwrap();
+        assert!(users.claim("another", &[1u8; 32]).is_err());
+        fs::remove_file(&path).ok();
+    }
+}